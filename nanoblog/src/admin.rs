@@ -0,0 +1,161 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read};
+use std::path::Path;
+use std::thread;
+
+use futures::executor::block_on;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+
+use crate::config::{self, Config};
+use crate::db::{Database, ExportedPost, Post, PostMetadata};
+use crate::markdown;
+use crate::posts;
+use crate::sanitize;
+use crate::templates;
+
+const TOKEN_LENGTH: usize = 40;
+
+fn other<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::Other, e.to_string())
+}
+
+/// Print the effective configuration, with secrets masked.
+pub fn check_config(config: &Config) -> io::Result<()> {
+    let mut shown = config.clone();
+    let mask = |s: &mut Option<String>| {
+        if s.is_some() {
+            *s = Some("********".into());
+        }
+    };
+    mask(&mut shown.storage.redis_password);
+    mask(&mut shown.auth.metrics_password);
+    mask(&mut shown.auth.metrics_token);
+    for sub in &mut shown.webhooks.subscriptions {
+        sub.secret = "********".into();
+    }
+
+    let toml = toml::to_string_pretty(&shown).map_err(other)?;
+    println!("{}", toml);
+    eprintln!("Configuration OK");
+    Ok(())
+}
+
+pub fn migrate(db: &Database) -> io::Result<()> {
+    let found = block_on(db.migrate()).map_err(other)?;
+    println!("Migrated {} posts", found);
+    Ok(())
+}
+
+/// Backfill storage in the background if `migrate` has never run against
+/// it, so a server upgraded in place doesn't wait on someone to run it.
+pub fn start_migration(db: Database) {
+    thread::spawn(move || {
+        match block_on(db.is_migrated()) {
+            Ok(true) => {},
+            Ok(false) => match block_on(db.migrate()) {
+                Ok(found) => eprintln!("Migrated {} posts", found),
+                Err(e) => eprintln!("Storage migration failed, run `nanoblog migrate`: {}", e),
+            },
+            Err(e) => eprintln!("Can't tell whether storage needs migrating: {}", e),
+        }
+    });
+}
+
+pub fn create_token(db: &Database, admin: bool) -> io::Result<()> {
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .collect();
+    block_on(db.add_token(token.clone(), admin)).map_err(other)?;
+    println!("{}", token);
+    Ok(())
+}
+
+pub fn revoke_token(db: &Database, token: String) -> io::Result<()> {
+    if !block_on(db.revoke_token(token)).map_err(other)? {
+        return Err(Error::new(ErrorKind::NotFound, "No such token"));
+    }
+    Ok(())
+}
+
+/// Write every saved post as a JSON array, to a file or stdout.
+pub fn export(db: &Database, output: Option<&Path>) -> io::Result<()> {
+    let published = block_on(db.list_posts());
+    let mut slugs = block_on(db.all_slugs()).map_err(other)?;
+    slugs.sort();
+
+    let mut posts = vec![];
+    for slug in slugs {
+        // Skip posts deleted since the slugs were listed.
+        let post = match block_on(db.get_post(slug.clone())).map_err(other)? {
+            Some(post) => post,
+            None => continue,
+        };
+        posts.push(ExportedPost {
+            published: published.contains_key(&slug),
+            post,
+        });
+    }
+
+    match output {
+        Some(path) => serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &posts),
+        None => serde_json::to_writer_pretty(io::stdout(), &posts),
+    }
+    .map_err(other)?;
+    eprintln!("Exported {} posts", posts.len());
+    Ok(())
+}
+
+/// Save every post from an `export` file, publishing those marked as such.
+/// Posts are stored as-is, keeping their dates and revisions, though they
+/// count as updated now for `Last-Modified`.
+pub fn import(db: &Database, input: &Path) -> io::Result<()> {
+    let posts: Vec<ExportedPost> = serde_json::from_reader(BufReader::new(File::open(input)?))
+        .map_err(other)?;
+
+    let count = posts.len();
+    for ExportedPost { mut post, published } in posts {
+        // Revisions carry on from any post deleted under the same slug here.
+        let last_revision = block_on(db.get_revision(post.slug.clone()));
+        let exists = block_on(db.get_post(post.slug.clone())).map_err(other)?.is_some();
+        if !exists && post.revision <= last_revision {
+            post.revision = last_revision + 1;
+        }
+        post.touch();
+        block_on(db.save_post(post.clone())).map_err(other)?;
+        if published {
+            block_on(db.activate_post(PostMetadata::from(post))).map_err(other)?;
+        }
+    }
+    eprintln!("Imported {} posts", count);
+    Ok(())
+}
+
+/// Render a markdown file the same way the server would. By default the
+/// whole page is produced, or only the post body with `body_only`.
+pub fn render(db: &Database, file: &Path, title: Option<String>, body_only: bool, trusted_html: bool) -> io::Result<()> {
+    let mut body = String::new();
+    File::open(file)?.read_to_string(&mut body)?;
+    let assets = block_on(posts::post_assets(db, &body));
+
+    if body_only {
+        let rendered = markdown::render(&body, &assets);
+        let html = if trusted_html { rendered.html } else { sanitize::clean(&rendered.html) };
+        println!("{}", html);
+        return Ok(());
+    }
+
+    templates::load(&config::get().render).map_err(other)?;
+    let title = title.unwrap_or_else(|| {
+        file.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()
+    });
+    let post = Post {
+        title,
+        body,
+        trusted_html,
+        ..Post::default()
+    };
+    println!("{}", posts::render_post(&post, &assets).map_err(other)?);
+    Ok(())
+}
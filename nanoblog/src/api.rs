@@ -1,92 +1,783 @@
-use tide::{Context, EndpointResult, Error, http};
-use crate::db;
+use futures::prelude::*;
+use serde::Serialize;
+use tide::{Context, EndpointResult, Response, http};
+use http::header::{self, HeaderMap};
+use crate::assets;
+use crate::batch;
+use crate::changes;
+use crate::db::{self, WebhookEvent};
+use crate::error::{ApiError, ErrorCode};
+use crate::http_cache::{self, RouteClass, Validators};
+use crate::openapi::{Body, Method, Operation};
+use crate::posts;
+use crate::templates;
+use crate::webhooks::{self, Event};
 use http::status::StatusCode;
-use tide::error::ResultExt;
-use chrono::Local;
+use chrono::{Local, NaiveDate};
+use slug::slugify;
 
-pub async fn list_posts(cx: Context<db::Database>) -> EndpointResult {
-    let client = cx.app_data().to_owned();
-    let posts = client.list_posts().await;
-    let body = serde_json::to_string(&posts)
+pub const JSON: &str = "application/json";
+const MARKDOWN: &str = "text/markdown";
+const HTML: &str = "text/html";
+const MERGE_PATCH: &str = "application/merge-patch+json";
+
+/// Post fields a `PATCH` may change. The slug is the post's identity, and
+/// the revision and update date are maintained by the server.
+pub const PATCHABLE: &[&str] = &["title", "body", "tags", "trusted_html", "date_created"];
+
+/// Pick the offered media type the client prefers, by the quality its most
+/// specific matching `Accept` range gives. Ties go to the earlier offer, and
+/// a missing header accepts anything.
+pub fn negotiate(headers: &HeaderMap, offered: &[&'static str]) -> Option<&'static str> {
+    let accept = match headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(a) if !a.trim().is_empty() => a,
+        _ => return offered.first().cloned(),
+    };
+
+    let ranges: Vec<(&str, f32)> = accept.split(',')
+        .map(|item| {
+            let mut parts = item.split(';').map(|p| p.trim());
+            let range = parts.next().unwrap_or_default();
+            let q = parts
+                .find(|p| p.starts_with("q="))
+                .and_then(|p| p[2..].parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (range, q)
+        })
+        .collect();
+
+    let mut best: Option<(&'static str, f32)> = None;
+    for media in offered {
+        let kind = media.split('/').next().unwrap_or_default();
+        let q = ranges.iter()
+            .filter_map(|(range, q)| {
+                if range.eq_ignore_ascii_case(media) {
+                    Some((2, *q))
+                } else if range.ends_with("/*") && range[..range.len() - 2].eq_ignore_ascii_case(kind) {
+                    Some((1, *q))
+                } else if *range == "*/*" {
+                    Some((0, *q))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, q)| q)
+            .unwrap_or(0.0);
+        if q > 0.0 && best.map_or(true, |(_, b)| q > b) {
+            best = Some((media, q));
+        }
+    }
+    best.map(|(media, _)| media)
+}
+
+pub fn not_acceptable(offered: &[&str]) -> ApiError {
+    ApiError::new(ErrorCode::NotAcceptable, format!("This resource is available as {}", offered.join(", ")))
+        .with_details(serde_json::json!({ "available": offered }))
+}
+
+/// Fail unless the client accepts a JSON response.
+pub fn accept_json(headers: &HeaderMap) -> Result<(), ApiError> {
+    negotiate(headers, &[JSON])
+        .map(|_| ())
+        .ok_or_else(|| not_acceptable(&[JSON]))
+}
+
+/// Fail unless the request body is labelled as one of `accepted`.
+fn require_body_type(headers: &HeaderMap, accepted: &[&str]) -> Result<(), ApiError> {
+    let content_type = headers.get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
         .unwrap_or_default();
+    let media = content_type.split(';').next().unwrap_or_default().trim();
+    if accepted.iter().any(|a| media.eq_ignore_ascii_case(a)) {
+        Ok(())
+    } else {
+        Err(ApiError::new(ErrorCode::UnsupportedMediaType, format!("Request bodies must be {}", accepted.join(" or ")))
+            .with_details(serde_json::json!({ "content_type": content_type })))
+    }
+}
+
+/// Fail unless the request body is labelled as JSON.
+pub fn require_json_body(headers: &HeaderMap) -> Result<(), ApiError> {
+    require_body_type(headers, &[JSON])
+}
+
+pub fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response {
+    let body = serde_json::to_string(value)
+        .unwrap_or_default();
+    http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, JSON)
+        .body(format!("{}\n", body).into())
+        .expect("Error building response")
+}
+
+/// Every API endpoint, relative to the API root. Routes are registered from
+/// this table and `/openapi.json` documents it.
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            id: "ping",
+            method: Method::Get,
+            path: "/ping",
+            summary: "Check the API is up and the token is valid",
+            query: &[],
+            request: None,
+            status: 200,
+            response: None,
+            alternates: &["text/plain"],
+            errors: &[],
+            handler: |cx| ping(cx).boxed(),
+        },
+        Operation {
+            id: "listPosts",
+            method: Method::Get,
+            path: "/posts",
+            summary: "List posts, newest first",
+            query: &[
+                ("status", "`published`, `scheduled` or `draft`. Defaults to published and scheduled posts"),
+                ("tag", "Only posts with this tag"),
+                ("from", "Only posts created on or after this YYYY-MM-DD date"),
+                ("to", "Only posts created on or before this YYYY-MM-DD date"),
+            ],
+            request: None,
+            status: 200,
+            response: Some("PostList"),
+            alternates: &[],
+            errors: &[ErrorCode::BadRequest, ErrorCode::NotAcceptable, ErrorCode::StorageUnavailable],
+            handler: |cx| list_posts(cx).boxed(),
+        },
+        Operation {
+            id: "upsertPost",
+            method: Method::Post,
+            path: "/posts",
+            summary: "Create or replace a post, keyed by the slug of its title",
+            query: &[("draft", "`true` to save without publishing")],
+            request: Some(Body::Json("NewPost")),
+            status: 200,
+            response: Some("Post"),
+            alternates: &[],
+            errors: &[
+                ErrorCode::InvalidBody,
+                ErrorCode::Forbidden,
+                ErrorCode::UnsupportedMediaType,
+                ErrorCode::NotAcceptable,
+                ErrorCode::StorageUnavailable,
+            ],
+            handler: |cx| upsert_post(cx).boxed(),
+        },
+        Operation {
+            id: "getPost",
+            method: Method::Get,
+            path: "/posts/:post",
+            summary: "Fetch a post, published or not",
+            query: &[],
+            request: None,
+            status: 200,
+            response: Some("Post"),
+            alternates: &[MARKDOWN, HTML],
+            errors: &[
+                ErrorCode::BadRequest,
+                ErrorCode::NotFound,
+                ErrorCode::NotAcceptable,
+                ErrorCode::Internal,
+                ErrorCode::StorageUnavailable,
+            ],
+            handler: |cx| get_raw_post(cx).boxed(),
+        },
+        Operation {
+            id: "patchPost",
+            method: Method::Patch,
+            path: "/posts/:post",
+            summary: "Change some of a post's fields with a JSON merge patch, recording a revision",
+            query: &[],
+            request: Some(Body::MergePatch("PostPatch")),
+            status: 200,
+            response: Some("Post"),
+            alternates: &[],
+            errors: &[
+                ErrorCode::BadRequest,
+                ErrorCode::InvalidBody,
+                ErrorCode::Forbidden,
+                ErrorCode::NotFound,
+                ErrorCode::PreconditionFailed,
+                ErrorCode::UnsupportedMediaType,
+                ErrorCode::NotAcceptable,
+                ErrorCode::StorageUnavailable,
+            ],
+            handler: |cx| patch_post(cx).boxed(),
+        },
+        Operation {
+            id: "deletePost",
+            method: Method::Delete,
+            path: "/posts/:post",
+            summary: "Delete a post, published or not, and its history",
+            query: &[],
+            request: None,
+            status: 204,
+            response: None,
+            alternates: &[],
+            errors: &[ErrorCode::BadRequest, ErrorCode::NotFound, ErrorCode::StorageUnavailable],
+            handler: |cx| delete_post(cx).boxed(),
+        },
+        Operation {
+            id: "batchPosts",
+            method: Method::Post,
+            path: "/posts:batch",
+            summary: "Apply a list of upserts, publishes, unpublishes and deletes, reporting on each",
+            query: &[],
+            request: Some(Body::Json("BatchRequest")),
+            status: 200,
+            response: Some("BatchResponse"),
+            alternates: &[],
+            errors: &[
+                ErrorCode::InvalidBody,
+                ErrorCode::PayloadTooLarge,
+                ErrorCode::UnsupportedMediaType,
+                ErrorCode::NotAcceptable,
+                ErrorCode::StorageUnavailable,
+            ],
+            handler: |cx| batch::apply(cx).boxed(),
+        },
+        Operation {
+            id: "listPostRevisions",
+            method: Method::Get,
+            path: "/posts/:post/revisions",
+            summary: "A post's partial update history, newest first",
+            query: &[],
+            request: None,
+            status: 200,
+            response: Some("PostRevisionList"),
+            alternates: &[],
+            errors: &[
+                ErrorCode::BadRequest,
+                ErrorCode::NotFound,
+                ErrorCode::NotAcceptable,
+                ErrorCode::StorageUnavailable,
+            ],
+            handler: |cx| list_revisions(cx).boxed(),
+        },
+        Operation {
+            id: "unpublishPost",
+            method: Method::Post,
+            path: "/posts/:post/unpublish",
+            summary: "Remove a post from the index, keeping it stored",
+            query: &[],
+            request: None,
+            status: 204,
+            response: None,
+            alternates: &[],
+            errors: &[ErrorCode::BadRequest, ErrorCode::NotFound, ErrorCode::StorageUnavailable],
+            handler: |cx| unpublish_post(cx).boxed(),
+        },
+        Operation {
+            id: "uploadAsset",
+            method: Method::Post,
+            path: "/assets",
+            summary: "Upload a file, served afterwards at the returned asset's URL",
+            query: &[("name", "File name to serve the asset under")],
+            request: Some(Body::Binary),
+            status: 201,
+            response: Some("Asset"),
+            alternates: &[],
+            errors: &[
+                ErrorCode::BadRequest,
+                ErrorCode::InvalidBody,
+                ErrorCode::PayloadTooLarge,
+                ErrorCode::Internal,
+                ErrorCode::StorageUnavailable,
+            ],
+            handler: |cx| assets::upload(cx).boxed(),
+        },
+        Operation {
+            id: "listChanges",
+            method: Method::Get,
+            path: "/changes",
+            summary: "Changes to posts and assets after a cursor, oldest first, or as an event stream",
+            query: &[
+                ("since", "Cursor from a previous page's `next`. Defaults to the oldest change kept"),
+                ("limit", "Changes per page, at most 1000. Defaults to 100"),
+            ],
+            request: None,
+            status: 200,
+            response: Some("ChangeList"),
+            alternates: &[changes::EVENT_STREAM],
+            errors: &[
+                ErrorCode::BadRequest,
+                ErrorCode::Gone,
+                ErrorCode::NotAcceptable,
+                ErrorCode::StorageUnavailable,
+            ],
+            handler: |cx| changes::list(cx).boxed(),
+        },
+        Operation {
+            id: "listWebhookDeliveries",
+            method: Method::Get,
+            path: "/webhooks/deliveries",
+            summary: "Webhook delivery attempts, newest first. Admin tokens only",
+            query: &[
+                ("subscription", "Only deliveries to this subscription"),
+                ("status", "`pending`, `delivered` or `failed`"),
+            ],
+            request: None,
+            status: 200,
+            response: Some("WebhookDeliveryList"),
+            alternates: &[],
+            errors: &[
+                ErrorCode::BadRequest,
+                ErrorCode::Forbidden,
+                ErrorCode::NotAcceptable,
+                ErrorCode::StorageUnavailable,
+            ],
+            handler: |cx| webhooks::list_deliveries(cx).boxed(),
+        },
+    ]
+}
+
+pub async fn ping(_cx: Context<db::Database>) -> EndpointResult {
     let res = http::Response::builder()
         .status(StatusCode::OK)
-        .body(format!("{}\n", body).into())
-        .client_err()?;
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body("OK\n".into())
+        .expect("Error building response");
     Ok(res)
 }
 
+/// Decode a `application/x-www-form-urlencoded` component.
+fn form_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let escaped = bytes.get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    },
+                    None => out.push(b'%'),
+                }
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The decoded value of a query string parameter.
+pub fn query_param(cx: &Context<db::Database>, name: &str) -> Option<String> {
+    cx.uri().query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|kv| {
+            let mut kv = kv.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if form_decode(k) == name => Some(form_decode(v)),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Which posts `list_posts` returns. Without a status, that's the ones on
+/// the index: published and scheduled.
+struct ListFilter {
+    status: Option<db::PostStatus>,
+    tag: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl ListFilter {
+    fn from_query(cx: &Context<db::Database>) -> Result<Self, ApiError> {
+        let status = match query_param(cx, "status") {
+            Some(s) => Some(db::PostStatus::parse(&s).ok_or_else(|| {
+                ApiError::bad_request(format!("Unknown status {:?}", s))
+                    .with_details(serde_json::json!({ "status": ["published", "scheduled", "draft"] }))
+            })?),
+            None => None,
+        };
+        let date = |name: &str| -> Result<Option<String>, ApiError> {
+            match query_param(cx, name) {
+                Some(d) => NaiveDate::parse_from_str(&d, "%F")
+                    .map(|_| Some(d.clone()))
+                    .map_err(|_| ApiError::bad_request(format!("`{}` must be a YYYY-MM-DD date", name))),
+                None => Ok(None),
+            }
+        };
+        Ok(Self {
+            status,
+            tag: query_param(cx, "tag"),
+            from: date("from")?,
+            to: date("to")?,
+        })
+    }
+
+    fn matches(&self, post: &db::PostSummary) -> bool {
+        let status = match self.status {
+            Some(status) => post.status == status,
+            None => post.status != db::PostStatus::Draft,
+        };
+        status
+            && self.tag.as_ref().map_or(true, |t| post.tags.contains(t))
+            && self.from.as_ref().map_or(true, |d| post.date_created >= *d)
+            && self.to.as_ref().map_or(true, |d| post.date_created <= *d)
+    }
+}
+
+/// Summaries of posts, newest first, narrowed by the `status`, `tag`, `from`
+/// and `to` query parameters.
+pub async fn list_posts(cx: Context<db::Database>) -> EndpointResult {
+    accept_json(cx.headers())?;
+    let filter = ListFilter::from_query(&cx)?;
+    let client = cx.app_data().to_owned();
+    let (revision, updated_at) = client.index_version().await;
+    let drafts = filter.status == Some(db::PostStatus::Draft);
+    let posts: Vec<db::PostSummary> = client.post_summaries(drafts)
+        .await
+        .map_err(ApiError::storage)?
+        .into_iter()
+        .filter(|p| filter.matches(p))
+        .collect();
+    let res = json_response(StatusCode::OK, &posts);
+    let v = Validators::api(revision, updated_at.as_ref().map(String::as_str))
+        .as_of(Local::today());
+    Ok(http_cache::respond(cx.headers(), RouteClass::Api, &v, res))
+}
+
+/// A post as JSON, its raw markdown, or rendered as it would be published,
+/// depending on `Accept`.
 pub async fn get_raw_post(cx: Context<db::Database>) -> EndpointResult {
+    const OFFERED: &[&str] = &[JSON, MARKDOWN, HTML];
+    let media = negotiate(cx.headers(), OFFERED)
+        .ok_or_else(|| not_acceptable(OFFERED))?;
+
     let client = cx.app_data().to_owned();
-    let title: String = cx.param("post").client_err()?;
-    client.get_post(title)
+    let title: String = cx.param("post")
+        .map_err(|_| ApiError::bad_request("Invalid post slug"))?;
+    let p = client.get_post(title.clone())
         .await
-        .map(|p| {
-            let body = serde_json::to_string(&p)
-                .unwrap_or_default();
+        .map_err(ApiError::storage)?
+        .ok_or_else(|| ApiError::not_found(format!("No post named {:?}", title)))?;
+
+    let (mut res, etag) = match media {
+        MARKDOWN => {
             let res = http::Response::builder()
                 .status(StatusCode::OK)
-                .body(format!("{}\n", body).into())
-                .expect("Error unwrapping raw post body");
-            Ok(res)
-        })
-        .map_err(|e| {
+                .header(header::CONTENT_TYPE, "text/markdown; charset=UTF-8")
+                .body(p.body.clone().into())
+                .expect("Error building response");
+            (res, format!("\"{:x}-md\"", p.revision))
+        },
+        HTML => {
+            let assets = posts::post_assets(&client, &p.body).await;
+            let page = posts::render_post(&p, &assets)
+                .map_err(|e| ApiError::internal("Failed to render post").with_details(
+                    serde_json::json!({ "error": format!("{:?}", e) })
+                ))?;
             let res = http::Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(e.into())
-                .expect("Error generating error response");
-            Error::from(res)
-        })?
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "text/html; charset=UTF-8")
+                .body(page.into())
+                .expect("Error building response");
+            (res, format!("\"{:x}-html-{}\"", p.revision, templates::version()))
+        },
+        _ => {
+            let v = Validators::api(p.revision, None);
+            (json_response(StatusCode::OK, &p), v.etag)
+        },
+    };
+
+    res.headers_mut().insert(header::VARY, header::HeaderValue::from_static("Accept"));
+    let updated_at = p.updated_at.as_ref().map(String::as_str);
+    let last_modified = match media {
+        HTML => Validators::page(p.revision, updated_at).last_modified,
+        _ => Validators::api(p.revision, updated_at).last_modified,
+    };
+    let v = Validators { etag, last_modified };
+    Ok(http_cache::respond(cx.headers(), RouteClass::Api, &v, res))
 }
 
 
-pub async fn upsert_post(mut cx: Context<db::Database>) -> EndpointResult {
-    let client = cx.app_data().to_owned();
-    let post = cx.body_json::<db::NewPost>().await.client_err()?;
-    let now = Local::today().format("%F").to_string();
+pub fn is_admin(cx: &Context<db::Database>) -> bool {
+    cx.extensions().get::<db::TokenRole>() == Some(&db::TokenRole::Admin)
+}
 
-    let draft = cx.uri().query()
-        .unwrap_or_default()
-        .contains("draft=true");
+/// Fail unless the request's token may publish trusted HTML.
+pub fn check_trusted(is_admin: bool, trusted_html: bool) -> Result<(), ApiError> {
+    if trusted_html && !is_admin {
+        return Err(ApiError::forbidden("Only admin tokens may publish trusted HTML"));
+    }
+    Ok(())
+}
 
-    let mut post: db::Post = post.into();
-    if let Ok(p) = client.get_post(post.slug.clone()).await {
+/// The post stored by upserting `new` over `existing`, the post already saved
+/// under the same slug if there is one. `last_revision` is the slug's latest
+/// revision, which carries on past deleted posts.
+pub fn upserted(new: db::NewPost, existing: Option<db::Post>, last_revision: u64) -> db::Post {
+    let keep_tags = new.tags.is_none();
+    let mut post: db::Post = new.into();
+    post.revision = last_revision + 1;
+    post.touch();
+    if let Some(p) = existing {
         post.date_created = p.date_created;
-        post.date_updated = Some(now);
+        post.date_updated = Some(Local::today().format("%F").to_string());
+        post.revision = p.revision.max(last_revision) + 1;
+        if keep_tags {
+            post.tags = p.tags;
+        }
     }
+    post
+}
+
+pub async fn upsert_post(mut cx: Context<db::Database>) -> EndpointResult {
+    require_json_body(cx.headers())?;
+    accept_json(cx.headers())?;
+    let client = cx.app_data().to_owned();
+    let draft = match query_param(&cx, "draft") {
+        Some(d) => d.parse::<bool>()
+            .map_err(|_| ApiError::bad_request("`draft` must be true or false"))?,
+        None => false,
+    };
+    let post = cx.body_json::<db::NewPost>().await.map_err(ApiError::invalid_body)?;
 
-    let res = client.save_post(post.clone())
+    check_trusted(is_admin(&cx), post.trusted_html)?;
+
+    let slug = slugify(&post.title);
+    let existing = client.get_post(slug.clone()).await.map_err(ApiError::storage)?;
+    let last_revision = client.get_revision(slug.clone()).await;
+    let was_published = existing.is_some() && client.is_published(slug).await;
+    let kind = if existing.is_some() { WebhookEvent::PostUpdated } else { WebhookEvent::PostCreated };
+    let post = upserted(post, existing, last_revision);
+
+    client.save_post(post.clone())
         .await
-        .map(|p| {
-            let body = serde_json::to_string(&p)
-                .unwrap_or_default();
-            let res = http::Response::builder()
-                .status(StatusCode::OK)
-                .body(format!("{}\n", body).into())
-                .expect("Error unwrapping raw post body");
-            Ok(res)
-        })
-        .map_err(|e| {
-            let res = http::Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(e.into())
-                .expect("Error generating error response");
-            Error::from(res)
-        })?;
+        .map_err(ApiError::storage)?;
+
+    let res = json_response(StatusCode::OK, &post);
 
     if draft {
-        return res
+        webhooks::fire(&client, vec![Event::post(kind, &post, was_published)]).await;
+        return Ok(res);
+    }
+
+    client.activate_post(post.clone().into())
+        .await
+        .map_err(ApiError::storage)?;
+
+    let mut events = vec![Event::post(kind, &post, true)];
+    if !was_published {
+        events.push(Event::post(WebhookEvent::PostPublished, &post, true));
+    }
+    webhooks::fire(&client, events).await;
+    Ok(res)
+}
+
+/// Remove a post, published or not, and its history.
+pub async fn delete_post(cx: Context<db::Database>) -> EndpointResult {
+    let client = cx.app_data().to_owned();
+    let slug: String = cx.param("post")
+        .map_err(|_| ApiError::bad_request("Invalid post slug"))?;
+    client.get_post(slug.clone())
+        .await
+        .map_err(ApiError::storage)?
+        .ok_or_else(|| ApiError::not_found(format!("No post named {:?}", slug)))?;
+
+    client.delete_post(slug.clone())
+        .await
+        .map_err(ApiError::storage)?;
+    webhooks::fire(&client, vec![Event::deleted(slug)]).await;
+
+    let res = http::Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Vec::new().into())
+        .expect("Error building response");
+    Ok(res)
+}
+
+/// Apply an RFC 7396 JSON merge patch: objects merge key by key, `null`
+/// removes a key and anything else replaces the target outright.
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let patch = match patch.as_object() {
+        Some(p) => p,
+        None => {
+            *target = patch.clone();
+            return;
+        },
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let target = target.as_object_mut().expect("target was just made an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
+/// Update some of a post's fields with a JSON merge patch. Besides the post's
+/// own fields, `"draft": true` unpublishes it and `"draft": false` publishes
+/// it. An `If-Match` header guards against overwriting someone else's change;
+/// it's compared again as the post is written, so a change landing in between
+/// fails the request rather than being lost.
+pub async fn patch_post(mut cx: Context<db::Database>) -> EndpointResult {
+    require_body_type(cx.headers(), &[MERGE_PATCH, JSON])?;
+    accept_json(cx.headers())?;
+    let client = cx.app_data().to_owned();
+    let slug: String = cx.param("post")
+        .map_err(|_| ApiError::bad_request("Invalid post slug"))?;
+    let mut patch = cx.body_json::<serde_json::Value>().await.map_err(ApiError::invalid_body)?;
+
+    let fields = patch.as_object_mut()
+        .ok_or_else(|| ApiError::invalid_body("A patch must be a JSON object"))?;
+    let draft = match fields.remove("draft") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::Bool(d)) => Some(d),
+        Some(_) => return Err(ApiError::invalid_body("`draft` must be a boolean").into()),
+    };
+    if let Some(field) = fields.keys().find(|k| !PATCHABLE.contains(&k.as_str())) {
+        return Err(ApiError::invalid_body(format!("`{}` can't be patched", field))
+            .with_details(serde_json::json!({ "field": field, "patchable": PATCHABLE }))
+            .into());
     }
+    // A trusted post's body is published unsanitized, so writing to it takes
+    // the same token as marking it trusted in the first place.
+    let touches_html = fields.contains_key("body") || fields.contains_key("trusted_html");
+    if let Some(date) = fields.get("date_created") {
+        let valid = date.as_str().map_or(false, |d| NaiveDate::parse_from_str(d, "%F").is_ok());
+        if !valid {
+            return Err(ApiError::invalid_body("`date_created` must be a YYYY-MM-DD date").into());
+        }
+    }
+
+    let current = client.get_post(slug.clone())
+        .await
+        .map_err(ApiError::storage)?
+        .ok_or_else(|| ApiError::not_found(format!("No post named {:?}", slug)))?;
+    let v = Validators::api(current.revision, None);
+    let changed_since = || ApiError::new(ErrorCode::PreconditionFailed, "The post has changed since it was fetched")
+        .with_details(serde_json::json!({ "etag": v.etag }));
+    if !http_cache::if_match(cx.headers(), &v) {
+        return Err(changed_since().into());
+    }
+
+    let mut merged = serde_json::to_value(&current)
+        .map_err(|e| ApiError::internal(format!("Can't serialize post: {}", e)))?;
+    merge_patch(&mut merged, &patch);
+    let mut post: db::Post = serde_json::from_value(merged).map_err(ApiError::invalid_body)?;
+    post.tags = db::clean_tags(post.tags);
+
+    check_trusted(is_admin(&cx), post.trusted_html && touches_html)?;
+
+    let was_published = client.is_published(slug.clone()).await;
+    let changed = serde_json::to_value(&post).ok() != serde_json::to_value(&current).ok();
+    let toggled = draft.map_or(false, |draft| draft == was_published);
+    if changed || toggled {
+        // Publishing or unpublishing is recorded like any other change, but
+        // only an edit to the post itself counts as an update.
+        let today = Local::today().format("%F").to_string();
+        post.revision = current.revision + 1;
+        post.touch();
+        if changed {
+            post.date_updated = Some(today.clone());
+        }
+        if let (Some(draft), Some(fields)) = (draft, patch.as_object_mut()) {
+            fields.insert("draft".into(), serde_json::Value::Bool(draft));
+        }
+        let revision = db::PostRevision {
+            revision: post.revision,
+            date: today,
+            changes: patch,
+        };
+        let saved = client.save_post_revision(post.clone(), revision, current.revision)
+            .await
+            .map_err(ApiError::storage)?;
+        if !saved {
+            return Err(changed_since().into());
+        }
+    }
+
+    // The index holds a copy of the title, dates and tags, so a published
+    // post's entry is refreshed along with it.
+    let publish = match draft {
+        Some(draft) => !draft,
+        None => changed && was_published,
+    };
+    if publish {
+        client.activate_post(post.clone().into())
+            .await
+            .map_err(ApiError::storage)?;
+    } else if draft == Some(true) {
+        client.deactivate_post(slug)
+            .await
+            .map_err(ApiError::storage)?;
+    }
+
+    let published = publish || (was_published && draft.is_none());
+    let mut events = vec![];
+    if changed {
+        events.push(Event::post(WebhookEvent::PostUpdated, &post, published));
+    }
+    if published != was_published {
+        let kind = if published { WebhookEvent::PostPublished } else { WebhookEvent::PostUnpublished };
+        events.push(Event::post(kind, &post, published));
+    }
+    webhooks::fire(&client, events).await;
+
+    let mut res = json_response(StatusCode::OK, &post);
+    if let Ok(etag) = header::HeaderValue::from_str(&Validators::api(post.revision, None).etag) {
+        res.headers_mut().insert(header::ETAG, etag);
+    }
+    Ok(res)
+}
+
+pub async fn list_revisions(cx: Context<db::Database>) -> EndpointResult {
+    accept_json(cx.headers())?;
+    let client = cx.app_data().to_owned();
+    let slug: String = cx.param("post")
+        .map_err(|_| ApiError::bad_request("Invalid post slug"))?;
+    let post = client.get_post(slug.clone())
+        .await
+        .map_err(ApiError::storage)?
+        .ok_or_else(|| ApiError::not_found(format!("No post named {:?}", slug)))?;
 
-    client.activate_post(post.into())
+    let revisions = client.post_revisions(slug)
         .await
-        .map_err(|e| {
-            let err = http::Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(e.into())
-                .expect("Error generating error response");
-            Error::from(err)
-        })?;
-
-    res
+        .map_err(ApiError::storage)?;
+    let res = json_response(StatusCode::OK, &revisions);
+    let v = Validators::api(post.revision, post.updated_at.as_ref().map(String::as_str));
+    Ok(http_cache::respond(cx.headers(), RouteClass::Api, &v, res))
+}
+
+pub async fn unpublish_post(cx: Context<db::Database>) -> EndpointResult {
+    let client = cx.app_data().to_owned();
+    let slug: String = cx.param("post")
+        .map_err(|_| ApiError::bad_request("Invalid post slug"))?;
+    let post = client.get_post(slug.clone())
+        .await
+        .map_err(ApiError::storage)?
+        .ok_or_else(|| ApiError::not_found(format!("No post named {:?}", slug)))?;
+    let was_published = client.is_published(slug.clone()).await;
+
+    client.deactivate_post(slug)
+        .await
+        .map_err(ApiError::storage)?;
+    if was_published {
+        webhooks::fire(&client, vec![Event::post(WebhookEvent::PostUnpublished, &post, false)]).await;
+    }
+
+    let res = http::Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Vec::new().into())
+        .expect("Error building response");
+    Ok(res)
 }
@@ -0,0 +1,229 @@
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+use serde_json::json;
+use tide::{http, Context, EndpointResult, error::ResultExt};
+use http::header::{self, HeaderValue};
+use http::status::StatusCode;
+
+use crate::api;
+use crate::config::{self, AssetBackend};
+use crate::db::{Asset, Database, Variant};
+use crate::error::{ApiError, ErrorCode};
+use crate::http_cache::{self, Validators};
+use crate::images;
+use crate::middleware::ClientInfo;
+use crate::posts;
+
+/// Assets never change under a hash, so clients can keep them indefinitely.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// Uploads may be arbitrary files, so browsers must neither sniff their
+/// type nor run anything they contain.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; style-src 'unsafe-inline'; sandbox";
+
+/// Reduce an uploaded file name to something safe to put in a URL path.
+fn clean_name(name: &str) -> String {
+    let name = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or_default();
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '-' })
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        "file".into()
+    } else {
+        name.into()
+    }
+}
+
+fn too_large(max_size: usize) -> ApiError {
+    ApiError::new(ErrorCode::PayloadTooLarge, format!("Assets are limited to {} bytes", max_size))
+        .with_details(json!({ "max_size": max_size }))
+}
+
+fn local_path(hash: &str) -> PathBuf {
+    config::get().storage.assets.dir.join(hash)
+}
+
+/// Hashes are hex SHA-256 digests; anything else can't name an asset, and
+/// mustn't reach the filesystem.
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase())
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// `photo.jpg` at 480 pixels wide as WebP becomes `photo-480w.webp`.
+fn variant_name(name: &str, width: u32, content_type: &str) -> String {
+    let stem = match name.rfind('.') {
+        Some(i) if i > 0 => &name[..i],
+        _ => name,
+    };
+    let ext = match content_type {
+        "image/webp" => "webp",
+        "image/png" => "png",
+        _ => "jpg",
+    };
+    format!("{}-{}w.{}", stem, width, ext)
+}
+
+/// Write an asset's contents to the configured backend and record it.
+async fn store(client: &Database, asset: Asset, data: Vec<u8>) -> Result<(), ApiError> {
+    let data = match config::get().storage.assets.backend {
+        AssetBackend::Redis => Some(data),
+        AssetBackend::Local => {
+            std::fs::write(local_path(&asset.hash), &data)
+                .map_err(|e| ApiError::internal("Can't write asset").with_details(json!({ "error": e.to_string() })))?;
+            None
+        },
+    };
+    client.save_asset(asset, data)
+        .await
+        .map_err(ApiError::storage)
+}
+
+/// Store the request body as an asset, named by the `name` query parameter.
+/// JPEG and PNG images have their metadata stripped and get resized and
+/// WebP variants. Responds with the asset's metadata; its URL is
+/// `/assets/<hash>/<name>`.
+pub async fn upload(mut cx: Context<Database>) -> EndpointResult {
+    let client = cx.app_data().to_owned();
+    let max_size = config::get().storage.assets.max_size;
+
+    let name = api::query_param(&cx, "name")
+        .ok_or_else(|| ApiError::bad_request("Missing `name` query parameter"))?;
+    let name = clean_name(&name);
+
+    let declared_size = cx.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared_size.map_or(false, |size| size > max_size) {
+        return Err(too_large(max_size).into());
+    }
+
+    let mut content_type = cx.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && !v.starts_with("application/octet-stream"))
+        .map(String::from)
+        .unwrap_or_else(|| mime_guess::guess_mime_type(&name).to_string());
+
+    let mut body = cx.body_bytes().await.map_err(ApiError::invalid_body)?;
+    if body.len() > max_size {
+        return Err(too_large(max_size).into());
+    }
+    if body.is_empty() {
+        return Err(ApiError::bad_request("Empty upload").into());
+    }
+
+    let mut dimensions = None;
+    let mut processed = vec![];
+    if let Some(image_type) = images::supported_type(&content_type, &body) {
+        let cfg = &config::get().storage.assets;
+        let image = images::process(body, image_type.into(), cfg.image_widths.clone(), cfg.webp)
+            .await
+            .map_err(|e| ApiError::invalid_body(format!("Invalid image: {}", e)))?;
+        body = image.original.data;
+        content_type = image.original.content_type.into();
+        dimensions = Some((image.original.width, image.original.height));
+        processed = image.variants;
+    }
+
+    let hash = hex_digest(&body);
+    let asset = match client.get_asset(hash.clone()).await
+        .map_err(ApiError::storage)?
+    {
+        // Already stored; only the name in the returned URL differs.
+        Some(existing) => Asset { name, ..existing },
+        None => {
+            let mut variants = vec![];
+            for image in processed {
+                let variant = Variant {
+                    hash: hex_digest(&image.data),
+                    name: variant_name(&name, image.width, image.content_type),
+                    content_type: image.content_type.into(),
+                    width: image.width,
+                    height: image.height,
+                };
+                let asset = Asset {
+                    hash: variant.hash.clone(),
+                    name: variant.name.clone(),
+                    content_type: variant.content_type.clone(),
+                    size: image.data.len() as u64,
+                    width: Some(image.width),
+                    height: Some(image.height),
+                    variants: vec![],
+                };
+                store(&client, asset, image.data).await?;
+                variants.push(variant);
+            }
+
+            let asset = Asset {
+                hash,
+                name,
+                content_type,
+                size: body.len() as u64,
+                width: dimensions.map(|(w, _)| w),
+                height: dimensions.map(|(_, h)| h),
+                variants,
+            };
+            store(&client, asset.clone(), body).await?;
+            asset
+        },
+    };
+
+    let body = serde_json::to_string(&asset).unwrap_or_default();
+    let location = ClientInfo::of(&cx).url(&asset.url()).unwrap_or_else(|| asset.url());
+    let res = http::Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::LOCATION, location)
+        .body(format!("{}\n", body).into())
+        .expect("Error building response");
+    Ok(res)
+}
+
+/// Serve an asset by hash. The name segment is only there for readable URLs
+/// and is ignored.
+pub async fn serve(cx: Context<Database>) -> EndpointResult {
+    let client = cx.app_data().to_owned();
+    let hash: String = cx.param("hash").client_err()?;
+    if !valid_hash(&hash) {
+        return posts::res_404();
+    }
+
+    let asset = match client.get_asset(hash.clone()).await {
+        Ok(Some(asset)) => asset,
+        _ => return posts::res_404(),
+    };
+
+    let v = Validators { etag: format!("\"{}\"", hash), last_modified: None };
+    let fresh = http_cache::is_fresh(cx.headers(), &v);
+    let body = if fresh {
+        Vec::new()
+    } else {
+        let data = match config::get().storage.assets.backend {
+            AssetBackend::Redis => client.get_asset_data(hash.clone()).await.ok().and_then(|d| d),
+            AssetBackend::Local => std::fs::read(local_path(&hash)).ok(),
+        };
+        match data {
+            Some(data) => data,
+            None => return posts::res_404(),
+        }
+    };
+
+    let mut res = http::Response::builder()
+        .status(if fresh { StatusCode::NOT_MODIFIED } else { StatusCode::OK })
+        .header(header::CONTENT_TYPE, asset.content_type.as_str())
+        .body(body.into())
+        .expect("Error building response");
+
+    let headers = res.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&v.etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE));
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(CONTENT_SECURITY_POLICY));
+    Ok(res)
+}
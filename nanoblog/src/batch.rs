@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use serde_json::json;
+use slug::slugify;
+use tide::{Context, EndpointResult};
+use tide::http::status::StatusCode;
+
+use crate::api;
+use crate::db::{
+    BatchItemResult, BatchMode, BatchOperation, BatchRequest, BatchResponse,
+    Database, Post, PostChange, WebhookEvent,
+};
+use crate::error::{ApiError, ErrorCode};
+use crate::webhooks::{self, Event};
+
+/// Bounds the work, and the Redis transaction, a single request can cause.
+pub const MAX_OPERATIONS: usize = 500;
+
+/// What one batch item changes, and what it reports back.
+struct Planned {
+    changes: Vec<PostChange>,
+    status: StatusCode,
+    post: Option<Post>,
+    events: Vec<Event>,
+}
+
+/// Works out the changes each operation makes, against the stored posts as
+/// modified by the operations planned before it.
+struct Planner {
+    client: Database,
+    is_admin: bool,
+    /// Posts looked up or changed so far; `None` for ones that don't exist.
+    posts: HashMap<String, Option<Post>>,
+    /// Whether each post looked up or changed so far is on the index.
+    listed: HashMap<String, bool>,
+    /// Latest revision of each slug looked up or changed so far.
+    revisions: HashMap<String, u64>,
+}
+
+impl Planner {
+    async fn post(&mut self, slug: String) -> Result<Option<Post>, ApiError> {
+        if let Some(post) = self.posts.get(&slug) {
+            return Ok(post.clone());
+        }
+        let post = self.client.get_post(slug.clone()).await.map_err(ApiError::storage)?;
+        self.posts.insert(slug, post.clone());
+        Ok(post)
+    }
+
+    async fn last_revision(&mut self, slug: String) -> u64 {
+        if let Some(revision) = self.revisions.get(&slug) {
+            return *revision;
+        }
+        let revision = self.client.get_revision(slug.clone()).await;
+        self.revisions.insert(slug, revision);
+        revision
+    }
+
+    async fn published(&mut self, slug: String) -> bool {
+        if let Some(listed) = self.listed.get(&slug) {
+            return *listed;
+        }
+        let listed = self.client.is_published(slug.clone()).await;
+        self.listed.insert(slug, listed);
+        listed
+    }
+
+    async fn existing(&mut self, slug: String) -> Result<Post, ApiError> {
+        let missing = ApiError::not_found(format!("No post named {:?}", slug));
+        self.post(slug).await?.ok_or(missing)
+    }
+
+    async fn plan(&mut self, op: BatchOperation) -> Result<Planned, ApiError> {
+        match op {
+            BatchOperation::Upsert { post, draft, date_created, slug } => {
+                if let Some(date) = &date_created {
+                    if NaiveDate::parse_from_str(date, "%F").is_err() {
+                        return Err(ApiError::invalid_body("`date_created` must be a YYYY-MM-DD date"));
+                    }
+                }
+                if let Some(slug) = &slug {
+                    if slug.is_empty() || slugify(slug) != *slug {
+                        return Err(ApiError::invalid_body(format!("{:?} isn't a valid slug", slug)));
+                    }
+                }
+                api::check_trusted(self.is_admin, post.trusted_html)?;
+
+                let slug = slug.unwrap_or_else(|| slugify(&post.title));
+                let existing = self.post(slug.clone()).await?;
+                let last_revision = self.last_revision(slug.clone()).await;
+                let was_published = existing.is_some() && self.published(slug.clone()).await;
+                let kind = if existing.is_some() { WebhookEvent::PostUpdated } else { WebhookEvent::PostCreated };
+                let mut post = api::upserted(post, existing.clone(), last_revision);
+                post.slug = slug;
+                if let Some(date) = date_created {
+                    post.date_created = date;
+                }
+
+                // Re-sending a post as it is stored leaves it alone, so syncing
+                // a whole directory only touches the posts that changed.
+                let mut changes = vec![];
+                let mut events = vec![];
+                let published = was_published || !draft;
+                let same = match existing {
+                    Some(ref e) => unchanged(e, &post),
+                    None => false,
+                };
+                match existing {
+                    Some(ref e) if same => post = e.clone(),
+                    _ => {
+                        self.posts.insert(post.slug.clone(), Some(post.clone()));
+                        self.revisions.insert(post.slug.clone(), post.revision);
+                        changes.push(PostChange::Save(post.clone()));
+                        events.push(Event::post(kind, &post, published));
+                    },
+                }
+                // The index entry only needs refreshing if the post changed.
+                if !draft && !(same && was_published) {
+                    changes.push(PostChange::Activate(post.clone().into()));
+                    self.listed.insert(post.slug.clone(), true);
+                    if !was_published {
+                        events.push(Event::post(WebhookEvent::PostPublished, &post, true));
+                    }
+                }
+                Ok(Planned { changes, status: StatusCode::OK, post: Some(post), events })
+            },
+            BatchOperation::Publish { slug } => {
+                let post = self.existing(slug.clone()).await?;
+                let mut events = vec![];
+                if !self.published(slug.clone()).await {
+                    events.push(Event::post(WebhookEvent::PostPublished, &post, true));
+                }
+                self.listed.insert(slug, true);
+                Ok(Planned {
+                    changes: vec![PostChange::Activate(post.into())],
+                    status: StatusCode::NO_CONTENT,
+                    post: None,
+                    events,
+                })
+            },
+            BatchOperation::Unpublish { slug } => {
+                let post = self.existing(slug.clone()).await?;
+                let mut events = vec![];
+                if self.published(slug.clone()).await {
+                    events.push(Event::post(WebhookEvent::PostUnpublished, &post, false));
+                }
+                self.listed.insert(slug.clone(), false);
+                Ok(Planned {
+                    changes: vec![PostChange::Deactivate(slug)],
+                    status: StatusCode::NO_CONTENT,
+                    post: None,
+                    events,
+                })
+            },
+            BatchOperation::Delete { slug } => {
+                self.existing(slug.clone()).await?;
+                let revision = self.last_revision(slug.clone()).await;
+                self.revisions.insert(slug.clone(), revision + 1);
+                self.posts.insert(slug.clone(), None);
+                self.listed.insert(slug.clone(), false);
+                Ok(Planned {
+                    changes: vec![PostChange::Delete(slug.clone())],
+                    status: StatusCode::NO_CONTENT,
+                    post: None,
+                    events: vec![Event::deleted(slug)],
+                })
+            },
+        }
+    }
+}
+
+fn unchanged(existing: &Post, post: &Post) -> bool {
+    existing.title == post.title
+        && existing.body == post.body
+        && existing.tags == post.tags
+        && existing.trusted_html == post.trusted_html
+        && existing.date_created == post.date_created
+}
+
+fn slug_of(op: &BatchOperation) -> String {
+    match op {
+        BatchOperation::Upsert { slug: Some(slug), .. } => slug.clone(),
+        BatchOperation::Upsert { post, .. } => slugify(&post.title),
+        BatchOperation::Publish { slug }
+        | BatchOperation::Unpublish { slug }
+        | BatchOperation::Delete { slug } => slug.clone(),
+    }
+}
+
+fn failed(op: &str, slug: String, e: ApiError) -> BatchItemResult {
+    BatchItemResult {
+        op: op.into(),
+        slug,
+        status: e.status().as_u16(),
+        post: None,
+        error: Some(e),
+    }
+}
+
+/// Apply a list of operations. Atomic batches store nothing unless every
+/// operation succeeds, in one transaction; best-effort ones store each
+/// operation that succeeds. Either way every item gets a result, and the
+/// response is a `200` unless storage itself fails.
+pub async fn apply(mut cx: Context<Database>) -> EndpointResult {
+    api::require_json_body(cx.headers())?;
+    api::accept_json(cx.headers())?;
+    let request = cx.body_json::<BatchRequest>().await.map_err(ApiError::invalid_body)?;
+    if request.operations.len() > MAX_OPERATIONS {
+        return Err(ApiError::new(
+            ErrorCode::PayloadTooLarge,
+            format!("Batches are limited to {} operations", MAX_OPERATIONS),
+        )
+            .with_details(json!({ "max_operations": MAX_OPERATIONS }))
+            .into());
+    }
+
+    let client = cx.app_data().to_owned();
+    let mut planner = Planner {
+        client: client.clone(),
+        is_admin: api::is_admin(&cx),
+        posts: HashMap::new(),
+        listed: HashMap::new(),
+        revisions: HashMap::new(),
+    };
+
+    let mut results = vec![];
+    let mut changes = vec![];
+    let mut events = vec![];
+    for op in request.operations {
+        let name = op.name();
+        let slug = slug_of(&op);
+        let planned = match planner.plan(op).await {
+            Ok(planned) => planned,
+            Err(e) => {
+                results.push(failed(name, slug, e));
+                continue;
+            },
+        };
+
+        if request.mode == BatchMode::BestEffort {
+            if let Err(e) = client.apply_changes(planned.changes).await {
+                // The planner's copy of the post no longer matches storage.
+                planner.posts.remove(&slug);
+                planner.listed.remove(&slug);
+                planner.revisions.remove(&slug);
+                results.push(failed(name, slug, ApiError::storage(e)));
+                continue;
+            }
+            webhooks::fire(&client, planned.events).await;
+        } else {
+            changes.extend(planned.changes);
+            events.extend(planned.events);
+        }
+        results.push(BatchItemResult {
+            op: name.into(),
+            slug,
+            status: planned.status.as_u16(),
+            post: planned.post,
+            error: None,
+        });
+    }
+
+    let committed = match request.mode {
+        BatchMode::BestEffort => true,
+        BatchMode::Atomic if results.iter().any(|r| r.error.is_some()) => {
+            for result in results.iter_mut().filter(|r| r.error.is_none()) {
+                let e = ApiError::new(ErrorCode::Conflict, "Not applied, since another operation in the batch failed");
+                result.status = e.status().as_u16();
+                result.post = None;
+                result.error = Some(e);
+            }
+            false
+        },
+        BatchMode::Atomic => {
+            client.apply_changes(changes)
+                .await
+                .map_err(ApiError::storage)?;
+            webhooks::fire(&client, events).await;
+            true
+        },
+    };
+
+    let response = BatchResponse { mode: request.mode, committed, results };
+    Ok(api::json_response(StatusCode::OK, &response))
+}
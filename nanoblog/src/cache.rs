@@ -0,0 +1,75 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lru::LruCache;
+use prometheus::IntCounter;
+
+/// Cache key used for the index page.
+pub const INDEX_KEY: &str = "/";
+
+/// Capacity used when the cache is first touched.
+static CAPACITY: AtomicUsize = AtomicUsize::new(256);
+
+lazy_static! {
+    static ref PAGES: Mutex<LruCache<String, CachedPage>> =
+        Mutex::new(LruCache::new(CAPACITY.load(Ordering::SeqCst).max(1)));
+
+    static ref HITS: IntCounter = register_int_counter!(
+        "page_cache_hits_total",
+        "Rendered pages served from the in-process cache."
+    )
+    .unwrap();
+    static ref MISSES: IntCounter = register_int_counter!(
+        "page_cache_misses_total",
+        "Rendered pages that had to be rendered from scratch."
+    )
+    .unwrap();
+}
+
+/// Set the number of pages kept. Only effective before the first lookup.
+pub fn set_capacity(pages: usize) {
+    CAPACITY.store(pages, Ordering::SeqCst);
+}
+
+#[derive(Clone)]
+pub struct CachedPage {
+    pub revision: u64,
+    pub body: String,
+    /// When the content was stored, for `Last-Modified`.
+    pub updated_at: Option<String>,
+    /// The day the page was rendered on, for pages that list posts by date.
+    pub rendered_on: Option<String>,
+}
+
+/// Look up a rendered page. Entries rendered from an older revision are
+/// treated as a miss, which is how edits made through another replica are
+/// picked up.
+pub fn get(key: &str, revision: u64) -> Option<CachedPage> {
+    let mut pages = PAGES.lock().unwrap();
+    let hit = match pages.get(&key.to_string()) {
+        Some(page) if page.revision == revision => Some(page.clone()),
+        _ => None,
+    };
+
+    match hit {
+        Some(_) => HITS.inc(),
+        None => MISSES.inc(),
+    }
+    hit
+}
+
+pub fn put(key: &str, page: CachedPage) {
+    PAGES.lock().unwrap().put(key.to_string(), page);
+}
+
+/// Drop a page, along with the index which lists it.
+pub fn invalidate(key: &str) {
+    let mut pages = PAGES.lock().unwrap();
+    pages.pop(&key.to_string());
+    pages.pop(&INDEX_KEY.to_string());
+}
+
+/// Drop every page, e.g. after the templates they were rendered with changed.
+pub fn clear() {
+    PAGES.lock().unwrap().clear();
+}
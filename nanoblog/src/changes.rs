@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::compat::Future01CompatExt;
+use futures::stream;
+use prometheus::IntGauge;
+use serde_json::json;
+use tide::{Context, EndpointResult, http};
+use tide::http::header;
+use tide::http::status::StatusCode;
+
+use crate::api;
+use crate::config;
+use crate::db::{Change, ChangeList, ChangeWindow, Database};
+use crate::error::{ApiError, ErrorCode};
+
+pub const EVENT_STREAM: &str = "text/event-stream";
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+lazy_static! {
+    static ref STREAMS: IntGauge = register_int_gauge!(
+        "change_feed_streams",
+        "Open change feed event streams."
+    )
+    .unwrap();
+}
+
+/// Where to read the feed from: the `since` query parameter, or for a
+/// reconnecting event stream its `Last-Event-ID`. `None` starts from the
+/// oldest change kept.
+fn cursor(cx: &Context<Database>) -> Result<Option<u64>, ApiError> {
+    let since = api::query_param(cx, "since").or_else(|| {
+        cx.headers().get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    });
+    match since {
+        Some(s) => s.trim().parse()
+            .map(Some)
+            .map_err(|_| ApiError::bad_request(format!("Invalid cursor {:?}", s))),
+        None => Ok(None),
+    }
+}
+
+/// Fail if changes right after `since` have already been dropped from the
+/// feed, since skipping them silently would leave the client out of sync.
+fn check_kept(since: u64, window: &ChangeWindow) -> Result<(), ApiError> {
+    match window.oldest {
+        Some(oldest) if since + 1 < oldest => Err(ApiError::new(
+            ErrorCode::Gone,
+            "Changes after this cursor are no longer kept; list the posts again and continue from `latest`",
+        )
+            .with_details(json!({ "oldest": oldest, "latest": window.latest }))),
+        _ => Ok(()),
+    }
+}
+
+/// Changes to posts and assets, oldest first, after the `since` cursor. As
+/// `text/event-stream` the response stays open and sends changes as they're
+/// made, each with its cursor as the event ID.
+pub async fn list(cx: Context<Database>) -> EndpointResult {
+    const OFFERED: &[&str] = &[api::JSON, EVENT_STREAM];
+    let media = api::negotiate(cx.headers(), OFFERED)
+        .ok_or_else(|| api::not_acceptable(OFFERED))?;
+    let since = cursor(&cx)?;
+    let limit = match api::query_param(&cx, "limit") {
+        Some(l) => match l.parse::<usize>() {
+            Ok(l) if l >= 1 && l <= MAX_LIMIT => l,
+            _ => return Err(ApiError::bad_request(format!("`limit` must be between 1 and {}", MAX_LIMIT)).into()),
+        },
+        None => DEFAULT_LIMIT,
+    };
+
+    let client = cx.app_data().to_owned();
+    let window = client.changes(since.unwrap_or_default(), limit)
+        .await
+        .map_err(ApiError::storage)?;
+    if let Some(since) = since {
+        check_kept(since, &window)?;
+    }
+
+    if media == EVENT_STREAM {
+        return Ok(event_stream(client, since.unwrap_or_default(), window.changes));
+    }
+
+    let next = window.changes.last().map_or(since.unwrap_or_default(), |c| c.seq);
+    let list = ChangeList {
+        changes: window.changes,
+        next,
+        more: next < window.latest,
+    };
+    Ok(api::json_response(StatusCode::OK, &list))
+}
+
+fn sse_event(change: &Change) -> String {
+    format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        change.seq,
+        change.kind.as_str(),
+        serde_json::to_string(change).unwrap_or_default(),
+    )
+}
+
+async fn sleep(duration: Duration) {
+    let _ = tokio_timer::Delay::new(Instant::now() + duration).compat().await;
+}
+
+/// State of one open event stream.
+struct Feed {
+    client: Database,
+    cursor: u64,
+    pending: VecDeque<Change>,
+    last_write: Instant,
+    poll: Duration,
+    heartbeat: Duration,
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        STREAMS.dec();
+    }
+}
+
+async fn next_chunk(mut feed: Feed) -> Option<(Result<Bytes, io::Error>, Feed)> {
+    loop {
+        if let Some(change) = feed.pending.pop_front() {
+            feed.cursor = change.seq;
+            feed.last_write = Instant::now();
+            return Some((Ok(Bytes::from(sse_event(&change))), feed));
+        }
+        if feed.last_write.elapsed() >= feed.heartbeat {
+            feed.last_write = Instant::now();
+            return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), feed));
+        }
+        match feed.client.changes(feed.cursor, DEFAULT_LIMIT).await {
+            Ok(ref window) if window.changes.is_empty() => sleep(feed.poll).await,
+            Ok(window) => feed.pending.extend(window.changes),
+            // Ending the stream has the client reconnect from its last event ID.
+            Err(_) => return None,
+        }
+    }
+}
+
+fn event_stream(client: Database, cursor: u64, first: Vec<Change>) -> tide::Response {
+    let config = config::get();
+    STREAMS.inc();
+    let feed = Feed {
+        client,
+        cursor,
+        pending: first.into_iter().collect(),
+        last_write: Instant::now(),
+        poll: Duration::from_millis(config.changes.poll_interval_ms),
+        heartbeat: Duration::from_secs(config.changes.heartbeat_secs),
+    };
+    http::Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, EVENT_STREAM)
+        .header(header::CACHE_CONTROL, "no-cache")
+        // Keeps nginx from holding events back in its response buffer.
+        .header("x-accel-buffering", "no")
+        .body(http_service::Body::from_stream(stream::unfold(feed, next_chunk)))
+        .expect("Error building response")
+}
@@ -0,0 +1,736 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use ipnet::IpNet;
+use serde::{Serialize, Deserialize};
+use tide::http;
+
+use crate::db::WebhookEvent;
+use crate::highlight;
+use crate::markdown::RenderOptions;
+use crate::sanitize;
+
+/// Config file read when neither `--config` nor `NANOBLOG_CONFIG` is given.
+/// It's fine for it not to exist.
+const DEFAULT_PATH: &str = "/etc/nanoblog/config.toml";
+
+lazy_static! {
+    static ref CURRENT: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+/// The active configuration.
+pub fn get() -> Arc<Config> {
+    CURRENT.read().unwrap().clone()
+}
+
+/// Replace the active configuration. This must happen before anything reads
+/// it, since most modules only consult it once.
+pub fn set(config: Config) {
+    *CURRENT.write().unwrap() = Arc::new(config);
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(&'static str, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "can't read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config in {}: {}", path.display(), e),
+            ConfigError::Env(var, value) => write!(f, "invalid value for {}: {:?}", var, value),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(e: ConfigError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub metrics: MetricsConfig,
+    pub storage: StorageConfig,
+    pub site: SiteConfig,
+    pub auth: AuthConfig,
+    pub cache: CacheConfig,
+    pub render: RenderConfig,
+    pub webhooks: WebhookConfig,
+    pub changes: ChangeFeedConfig,
+    pub rate_limit: RateLimitConfig,
+    pub proxy: ProxyConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    /// Response bodies smaller than this many bytes aren't compressed.
+    pub compression_min_size: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:80".into(),
+            compression_min_size: 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address of the metrics listener, or `None` to disable it.
+    pub listen: Option<String>,
+    /// Count views per published slug.
+    pub post_views: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen: Some("0.0.0.0:8000".into()),
+            post_views: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Redis,
+}
+
+/// Where uploaded asset contents are kept. Their metadata always lives in
+/// the storage backend.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetBackend {
+    Redis,
+    Local,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssetConfig {
+    pub backend: AssetBackend,
+    /// Directory holding asset contents with the `local` backend.
+    pub dir: PathBuf,
+    /// Largest accepted upload, in bytes.
+    pub max_size: usize,
+    /// Widths uploaded images are resized to, when narrower than the original.
+    pub image_widths: Vec<u32>,
+    /// Also store WebP versions of uploaded images.
+    pub webp: bool,
+}
+
+impl Default for AssetConfig {
+    fn default() -> Self {
+        Self {
+            backend: AssetBackend::Redis,
+            dir: PathBuf::from("assets"),
+            max_size: 10 * 1024 * 1024,
+            image_widths: vec![480, 960, 1600],
+            webp: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub redis_host: Option<String>,
+    pub redis_password: Option<String>,
+    pub assets: AssetConfig,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Redis,
+            redis_host: None,
+            redis_password: None,
+            assets: AssetConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NavLink {
+    pub title: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+    /// Prefix for every page title.
+    pub name: String,
+    /// Title of the index page.
+    pub title: String,
+    pub nav: Vec<NavLink>,
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        let link = |title: &str, url: &str| NavLink { title: title.into(), url: url.into() };
+        Self {
+            name: "Blog".into(),
+            title: "nanoblog".into(),
+            nav: vec![link("home", "/")],
+        }
+    }
+}
+
+/// Credentials protecting the metrics listener. Either a username and
+/// password for basic auth, or a bearer token.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub metrics_username: Option<String>,
+    pub metrics_password: Option<String>,
+    pub metrics_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheControlConfig {
+    pub index: String,
+    pub post: String,
+    pub feed: String,
+    pub api: String,
+}
+
+impl Default for CacheControlConfig {
+    fn default() -> Self {
+        Self {
+            index: "public, max-age=60".into(),
+            post: "public, max-age=300".into(),
+            feed: "public, max-age=300".into(),
+            api: "private, no-cache".into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Number of rendered pages kept in memory.
+    pub page_cache_size: usize,
+    pub cache_control: CacheControlConfig,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            page_cache_size: 256,
+            cache_control: CacheControlConfig::default(),
+        }
+    }
+}
+
+/// Extra tags, attributes and URL schemes allowed through HTML sanitization.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SanitizeConfig {
+    pub allow_tags: HashSet<String>,
+    pub allow_attributes: HashSet<String>,
+    pub url_schemes: HashSet<String>,
+}
+
+impl Default for SanitizeConfig {
+    fn default() -> Self {
+        Self {
+            allow_tags: HashSet::new(),
+            allow_attributes: HashSet::new(),
+            url_schemes: ["http", "https", "mailto"].iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    /// Directory holding one subdirectory per theme.
+    pub theme_dir: PathBuf,
+    /// Name of the theme under `theme_dir` to use. A theme has a `templates`
+    /// directory and a `static` directory served under `/static/`.
+    pub theme: String,
+    /// Use templates from this directory instead of the theme's.
+    pub template_dir: Option<PathBuf>,
+    /// Reload templates and static files when they change. Meant for working
+    /// on themes locally.
+    pub watch_templates: bool,
+    pub highlight_theme: String,
+    pub markdown: RenderOptions,
+    pub sanitize: SanitizeConfig,
+}
+
+impl RenderConfig {
+    pub fn templates(&self) -> PathBuf {
+        self.template_dir.clone()
+            .unwrap_or_else(|| self.theme_dir.join(&self.theme).join("templates"))
+    }
+
+    pub fn static_files(&self) -> PathBuf {
+        self.theme_dir.join(&self.theme).join("static")
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            theme_dir: PathBuf::from("themes"),
+            theme: "default".into(),
+            template_dir: None,
+            watch_templates: false,
+            highlight_theme: "InspiredGitHub".into(),
+            markdown: RenderOptions::default(),
+            sanitize: SanitizeConfig::default(),
+        }
+    }
+}
+
+/// An endpoint told about content changes by signed `POST`s.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Subscription {
+    /// Identifies the subscription in the delivery log and metrics.
+    pub name: String,
+    pub url: String,
+    /// Key for the `X-Nanoblog-Signature` HMAC.
+    pub secret: String,
+    /// Events to send. Empty means every event.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+impl Subscription {
+    pub fn wants(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Attempts at a delivery before it's marked failed.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubling after each further failure.
+    pub retry_delay_secs: u64,
+    pub max_retry_delay_secs: u64,
+    /// How long a receiver gets to respond.
+    pub timeout_secs: u64,
+    /// Number of delivery attempts kept for the delivery log.
+    pub log_size: usize,
+    pub subscriptions: Vec<Subscription>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            retry_delay_secs: 10,
+            max_retry_delay_secs: 3600,
+            timeout_secs: 10,
+            log_size: 1000,
+            subscriptions: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChangeFeedConfig {
+    /// Number of changes kept. Cursors older than that get a `410`.
+    pub log_size: usize,
+    /// How often event streams check for new changes.
+    pub poll_interval_ms: u64,
+    /// Idle event streams get a comment this often, so proxies keep them open.
+    pub heartbeat_secs: u64,
+}
+
+impl Default for ChangeFeedConfig {
+    fn default() -> Self {
+        Self {
+            log_size: 10_000,
+            poll_interval_ms: 1000,
+            heartbeat_secs: 15,
+        }
+    }
+}
+
+/// A token bucket: `burst` requests at once, refilled at `rate` per second.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Pages and assets, per client address.
+    pub public: Limit,
+    /// API requests, per bearer token, or per client address without one.
+    pub api: Limit,
+    /// Requests rejected for a bad bearer token, per client address. Once
+    /// they're used up, the address can't make API requests at all.
+    pub auth_failure: Limit,
+    /// Clients tracked at once, across every limit. The least recently seen
+    /// are forgotten first.
+    pub max_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            public: Limit { rate: 5.0, burst: 50 },
+            api: Limit { rate: 10.0, burst: 100 },
+            auth_failure: Limit { rate: 0.05, burst: 10 },
+            max_clients: 10_000,
+        }
+    }
+}
+
+/// Reverse proxies in front of the server, whose forwarding headers are
+/// believed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Proxy addresses, as CIDRs or single addresses. Empty ignores the
+    /// headers altogether.
+    pub trusted: Vec<String>,
+    /// The header the proxies record hops in. The other one is ignored,
+    /// since only the client could have sent it.
+    pub header: ForwardedHeader,
+}
+
+/// Headers a proxy can record the hops a request took in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`, along with `X-Forwarded-Proto` and `-Host`.
+    XForwarded,
+    /// The standard `Forwarded`.
+    Forwarded,
+}
+
+impl Default for ForwardedHeader {
+    fn default() -> Self {
+        ForwardedHeader::XForwarded
+    }
+}
+
+impl ProxyConfig {
+    /// The trusted networks. Call after `Config::validate`.
+    pub fn networks(&self) -> Vec<IpNet> {
+        self.trusted.iter().filter_map(|t| parse_network(t)).collect()
+    }
+}
+
+fn parse_network(s: &str) -> Option<IpNet> {
+    s.parse::<IpNet>().ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+fn env_string(name: &'static str, field: &mut String) {
+    if let Ok(v) = std::env::var(name) {
+        *field = v;
+    }
+}
+
+fn env_opt(name: &'static str, field: &mut Option<String>) {
+    if let Ok(v) = std::env::var(name) {
+        *field = Some(v);
+    }
+}
+
+fn env_parse<T: FromStr>(name: &'static str, field: &mut T) -> Result<(), ConfigError> {
+    if let Ok(v) = std::env::var(name) {
+        *field = v.parse().map_err(|_| ConfigError::Env(name, v))?;
+    }
+    Ok(())
+}
+
+fn env_flag(name: &'static str, field: &mut bool) -> Result<(), ConfigError> {
+    if let Ok(v) = std::env::var(name) {
+        *field = match v.to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => true,
+            "0" | "false" | "no" | "off" => false,
+            _ => return Err(ConfigError::Env(name, v)),
+        };
+    }
+    Ok(())
+}
+
+fn env_list(name: &'static str, field: &mut HashSet<String>) {
+    if let Ok(v) = std::env::var(name) {
+        *field = v.split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect();
+    }
+}
+
+impl Config {
+    /// Load the config file, if any. An explicitly requested file must exist,
+    /// while the default location is optional.
+    pub fn from_file(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let (path, required) = match path {
+            Some(p) => (p.to_path_buf(), true),
+            None => match std::env::var("NANOBLOG_CONFIG") {
+                Ok(p) => (PathBuf::from(p), true),
+                Err(_) => (PathBuf::from(DEFAULT_PATH), false),
+            },
+        };
+
+        if !required && !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| ConfigError::Io(path.clone(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    /// Apply overrides from the environment. These keep the variable names
+    /// used before the config file existed.
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_string("LISTEN_ADDR", &mut self.server.listen);
+        env_parse("COMPRESSION_MIN_SIZE", &mut self.server.compression_min_size)?;
+
+        if let Ok(v) = std::env::var("METRICS_ADDR") {
+            self.metrics.listen = if v.is_empty() || v == "off" { None } else { Some(v) };
+        }
+        env_flag("POST_VIEW_METRICS", &mut self.metrics.post_views)?;
+
+        env_opt("REDIS_HOST", &mut self.storage.redis_host);
+        env_opt("REDIS_PASSWORD", &mut self.storage.redis_password);
+        if let Ok(v) = std::env::var("ASSET_BACKEND") {
+            self.storage.assets.backend = match v.to_lowercase().as_str() {
+                "redis" => AssetBackend::Redis,
+                "local" => AssetBackend::Local,
+                _ => return Err(ConfigError::Env("ASSET_BACKEND", v)),
+            };
+        }
+        if let Ok(v) = std::env::var("ASSET_DIR") {
+            self.storage.assets.dir = PathBuf::from(v);
+        }
+        env_parse("ASSET_MAX_SIZE", &mut self.storage.assets.max_size)?;
+        if let Ok(v) = std::env::var("ASSET_IMAGE_WIDTHS") {
+            self.storage.assets.image_widths = v.split(',')
+                .map(str::trim)
+                .filter(|w| !w.is_empty())
+                .map(|w| w.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| ConfigError::Env("ASSET_IMAGE_WIDTHS", v.clone()))?;
+        }
+        env_flag("ASSET_WEBP", &mut self.storage.assets.webp)?;
+
+        env_string("SITE_NAME", &mut self.site.name);
+        env_string("SITE_TITLE", &mut self.site.title);
+
+        env_opt("METRICS_USERNAME", &mut self.auth.metrics_username);
+        env_opt("METRICS_PASSWORD", &mut self.auth.metrics_password);
+        env_opt("METRICS_TOKEN", &mut self.auth.metrics_token);
+
+        env_parse("PAGE_CACHE_SIZE", &mut self.cache.page_cache_size)?;
+        let cc = &mut self.cache.cache_control;
+        env_string("CACHE_CONTROL_INDEX", &mut cc.index);
+        env_string("CACHE_CONTROL_POST", &mut cc.post);
+        env_string("CACHE_CONTROL_FEED", &mut cc.feed);
+        env_string("CACHE_CONTROL_API", &mut cc.api);
+
+        if let Ok(v) = std::env::var("THEME_DIR") {
+            self.render.theme_dir = PathBuf::from(v);
+        }
+        env_string("THEME", &mut self.render.theme);
+        if let Ok(v) = std::env::var("TEMPLATE_DIR") {
+            self.render.template_dir = Some(PathBuf::from(v));
+        }
+        env_flag("WATCH_TEMPLATES", &mut self.render.watch_templates)?;
+        env_string("HIGHLIGHT_THEME", &mut self.render.highlight_theme);
+        let md = &mut self.render.markdown;
+        env_flag("MARKDOWN_FOOTNOTES", &mut md.footnotes)?;
+        env_flag("MARKDOWN_TASKLISTS", &mut md.tasklists)?;
+        env_flag("MARKDOWN_SMART_PUNCTUATION", &mut md.smart_punctuation)?;
+        env_flag("MARKDOWN_HEADING_ANCHORS", &mut md.heading_anchors)?;
+        env_flag("MARKDOWN_TOC", &mut md.toc)?;
+        let sanitize = &mut self.render.sanitize;
+        env_list("SANITIZE_ALLOW_TAGS", &mut sanitize.allow_tags);
+        env_list("SANITIZE_ALLOW_ATTRIBUTES", &mut sanitize.allow_attributes);
+        env_list("SANITIZE_URL_SCHEMES", &mut sanitize.url_schemes);
+
+        let hooks = &mut self.webhooks;
+        env_parse("WEBHOOK_MAX_ATTEMPTS", &mut hooks.max_attempts)?;
+        env_parse("WEBHOOK_RETRY_DELAY", &mut hooks.retry_delay_secs)?;
+        env_parse("WEBHOOK_MAX_RETRY_DELAY", &mut hooks.max_retry_delay_secs)?;
+        env_parse("WEBHOOK_TIMEOUT", &mut hooks.timeout_secs)?;
+        env_parse("WEBHOOK_LOG_SIZE", &mut hooks.log_size)?;
+
+        env_parse("CHANGE_LOG_SIZE", &mut self.changes.log_size)?;
+        env_parse("CHANGE_POLL_INTERVAL_MS", &mut self.changes.poll_interval_ms)?;
+        env_parse("CHANGE_HEARTBEAT", &mut self.changes.heartbeat_secs)?;
+
+        env_flag("RATE_LIMIT", &mut self.rate_limit.enabled)?;
+
+        if let Ok(v) = std::env::var("TRUSTED_PROXIES") {
+            self.proxy.trusted = v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Ok(v) = std::env::var("PROXY_HEADER") {
+            self.proxy.header = match v.to_lowercase().as_str() {
+                "x-forwarded" => ForwardedHeader::XForwarded,
+                "forwarded" => ForwardedHeader::Forwarded,
+                _ => return Err(ConfigError::Env("PROXY_HEADER", v)),
+            };
+        }
+        Ok(())
+    }
+
+    /// Check the config for mistakes that would otherwise only surface once
+    /// a request hits the affected code.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        if SocketAddr::from_str(&self.server.listen).is_err() {
+            return invalid(format!("server.listen is not a valid address: {:?}", self.server.listen));
+        }
+        if let Some(addr) = &self.metrics.listen {
+            if SocketAddr::from_str(addr).is_err() {
+                return invalid(format!("metrics.listen is not a valid address: {:?}", addr));
+            }
+            if *addr == self.server.listen {
+                return invalid("metrics.listen must differ from server.listen".into());
+            }
+        }
+
+        if self.storage.redis_host.as_ref().map_or(false, |h| h.is_empty()) {
+            return invalid("storage.redis_host is empty".into());
+        }
+        let assets = &self.storage.assets;
+        if assets.max_size == 0 {
+            return invalid("storage.assets.max_size must be at least 1".into());
+        }
+        if assets.image_widths.contains(&0) {
+            return invalid("storage.assets.image_widths can't contain 0".into());
+        }
+        if assets.backend == AssetBackend::Local && !assets.dir.is_dir() {
+            return invalid(format!("storage.assets.dir {} is not a directory", assets.dir.display()));
+        }
+
+        let auth = &self.auth;
+        if auth.metrics_username.is_some() != auth.metrics_password.is_some() {
+            return invalid("auth.metrics_username and auth.metrics_password must be set together".into());
+        }
+        if auth.metrics_username.is_some() && auth.metrics_token.is_some() {
+            return invalid("auth.metrics_token can't be combined with basic auth".into());
+        }
+
+        if self.cache.page_cache_size == 0 {
+            return invalid("cache.page_cache_size must be at least 1".into());
+        }
+        let cc = &self.cache.cache_control;
+        for (name, value) in &[("index", &cc.index), ("post", &cc.post), ("feed", &cc.feed), ("api", &cc.api)] {
+            if value.is_empty() || http::header::HeaderValue::from_str(value).is_err() {
+                return invalid(format!("cache.cache_control.{} is not a valid header value", name));
+            }
+        }
+
+        let theme = self.render.theme_dir.join(&self.render.theme);
+        if self.render.theme.is_empty() || !theme.is_dir() {
+            return invalid(format!("render.theme {:?} not found in {}", self.render.theme, self.render.theme_dir.display()));
+        }
+        if !self.render.templates().is_dir() {
+            return invalid(format!("template directory {} is missing", self.render.templates().display()));
+        }
+        if !highlight::theme_exists(&self.render.highlight_theme) {
+            return invalid(format!("render.highlight_theme {:?} is unknown", self.render.highlight_theme));
+        }
+        let sanitize = &self.render.sanitize;
+        if sanitize.url_schemes.is_empty() {
+            return invalid("render.sanitize.url_schemes can't be empty".into());
+        }
+        if let Some(tag) = sanitize.allow_tags.iter().find(|t| sanitize::RESERVED_TAGS.contains(&t.to_lowercase().as_str())) {
+            return invalid(format!("render.sanitize.allow_tags can't include {:?}, whose contents are always removed", tag));
+        }
+        if let Some(attr) = sanitize.allow_attributes.iter().find(|a| sanitize::RESERVED_ATTRIBUTES.contains(&a.to_lowercase().as_str())) {
+            return invalid(format!("render.sanitize.allow_attributes can't include {:?}, which is set on links by the sanitizer", attr));
+        }
+
+        let hooks = &self.webhooks;
+        if hooks.max_attempts == 0 {
+            return invalid("webhooks.max_attempts must be at least 1".into());
+        }
+        if hooks.timeout_secs == 0 {
+            return invalid("webhooks.timeout_secs must be at least 1".into());
+        }
+        if hooks.log_size == 0 {
+            return invalid("webhooks.log_size must be at least 1".into());
+        }
+        if hooks.max_retry_delay_secs < hooks.retry_delay_secs {
+            return invalid("webhooks.max_retry_delay_secs can't be below webhooks.retry_delay_secs".into());
+        }
+        let mut names = HashSet::new();
+        for sub in &hooks.subscriptions {
+            if sub.name.is_empty() || !names.insert(&sub.name) {
+                return invalid(format!("webhook subscription names must be unique and non-empty: {:?}", sub.name));
+            }
+            let valid_url = sub.url.parse::<http::Uri>().ok().map_or(false, |u| {
+                u.host().is_some() && (u.scheme_str() == Some("http") || u.scheme_str() == Some("https"))
+            });
+            if !valid_url {
+                return invalid(format!("webhook {:?} needs an http or https url", sub.name));
+            }
+            if sub.secret.is_empty() {
+                return invalid(format!("webhook {:?} has an empty secret", sub.name));
+            }
+        }
+
+        let changes = &self.changes;
+        if changes.log_size == 0 {
+            return invalid("changes.log_size must be at least 1".into());
+        }
+        if changes.poll_interval_ms == 0 || changes.heartbeat_secs == 0 {
+            return invalid("changes.poll_interval_ms and changes.heartbeat_secs must be at least 1".into());
+        }
+
+        let limits = &self.rate_limit;
+        for (name, limit) in &[("public", limits.public), ("api", limits.api), ("auth_failure", limits.auth_failure)] {
+            if !(limit.rate.is_finite() && limit.rate > 0.0) || limit.burst == 0 {
+                return invalid(format!("rate_limit.{} needs a positive rate and a burst of at least 1", name));
+            }
+        }
+        if limits.max_clients == 0 {
+            return invalid("rate_limit.max_clients must be at least 1".into());
+        }
+
+        if let Some(bad) = self.proxy.trusted.iter().find(|t| parse_network(t).is_none()) {
+            return invalid(format!("proxy.trusted entry {:?} is not a CIDR or address", bad));
+        }
+        Ok(())
+    }
+}
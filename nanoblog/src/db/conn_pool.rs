@@ -1,11 +1,14 @@
 #![allow(clippy::needless_lifetimes)]
 use r2d2_redis::{r2d2, redis, RedisConnectionManager};
 
+use futures01::Async;
 use futures01::future::poll_fn;
 use r2d2::{Pool, PooledConnection};
 
 use tokio_threadpool::blocking;
 
+use super::metrics;
+
 pub type ConnectionPool = Pool<RedisConnectionManager>;
 pub type Connection = PooledConnection<RedisConnectionManager>;
 
@@ -20,15 +23,12 @@ pub struct Database {
 }
 
 impl Database {
-    pub fn new() -> Self {
-        let host = std::env::var("REDIS_HOST");
-        let pw = std::env::var("REDIS_PASSWORD");
-
+    pub fn new(host: Option<&str>, pw: Option<&str>) -> Self {
         let conn_string = match (host, pw) {
-            (Ok(host), Err(_)) => redis::parse_redis_url(&format!("redis://{}", host)),
-            (Ok(host), Ok(pw)) => redis::parse_redis_url(&format!("redis://user:{}@{}", pw, host)),
-            (Err(_), Ok(pw)) => redis::parse_redis_url(&format!("redis://user:{}@localhost", pw)),
-            (_, _) => redis::parse_redis_url("redis://localhost"),
+            (Some(host), None) => redis::parse_redis_url(&format!("redis://{}", host)),
+            (Some(host), Some(pw)) => redis::parse_redis_url(&format!("redis://user:{}@{}", pw, host)),
+            (None, Some(pw)) => redis::parse_redis_url(&format!("redis://user:{}@localhost", pw)),
+            (None, None) => redis::parse_redis_url("redis://localhost"),
         };
 
         let manager = RedisConnectionManager::new(conn_string.unwrap()).unwrap();
@@ -39,6 +39,14 @@ impl Database {
         }
     }
 
+    /// Refresh the connection pool gauges.
+    pub fn record_pool_metrics(&self) {
+        let state = self.connection_pool.state();
+        metrics::POOL_SIZE.set(i64::from(self.connection_pool.max_size()));
+        metrics::POOL_IDLE.set(i64::from(state.idle_connections));
+        metrics::POOL_IN_USE.set(i64::from(state.connections - state.idle_connections));
+    }
+
     /// Runs the given closure in a way that is safe for blocking IO to the database.
     /// The closure will be passed a `Connection` from the pool to use.
     pub async fn run<F, T>(&self, f: F) -> T
@@ -52,10 +60,14 @@ impl Database {
         // `poll_fn` converts this into a future, then
         // `f.take()` allows the borrow checker to be sure `f` is not moved into the inner closure
         // multiple times if `poll_fn` is called multple times.
+        // Outside of a tokio threadpool (the admin subcommands) there is nowhere
+        // to hand blocking work off to, so it runs inline instead.
         let mut f = Some(f);
         poll_fn(|| {
-            blocking(|| (f.take().unwrap())(pool.get().unwrap()))
-                .map_err(|_| panic!("the threadpool shut down"))
+            match blocking(|| (f.take().unwrap())(pool.get().unwrap())) {
+                Err(_) if f.is_some() => Ok(Async::Ready((f.take().unwrap())(pool.get().unwrap()))),
+                res => res.map_err(|_| panic!("the threadpool shut down")),
+            }
         })
         .compat()
         .await
@@ -0,0 +1,79 @@
+use std::time::Instant;
+
+use prometheus::{HistogramVec, IntCounterVec, IntGauge, IntGaugeVec};
+
+/// Label identifying the storage backend behind `Database`.
+pub const BACKEND: &str = "redis";
+
+lazy_static! {
+    static ref OP_LATENCY: HistogramVec = register_histogram_vec!(
+        "storage_operation_duration_seconds",
+        "Storage operation latencies in seconds.",
+        &["backend", "operation", "outcome"]
+    )
+    .unwrap();
+    static ref OP_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "storage_operations_total",
+        "Storage operations by outcome.",
+        &["backend", "operation", "outcome"]
+    )
+    .unwrap();
+
+    pub static ref POOL_SIZE: IntGauge = register_int_gauge!(
+        "storage_pool_max_connections",
+        "Maximum number of connections the pool will open."
+    )
+    .unwrap();
+    pub static ref POOL_IDLE: IntGauge = register_int_gauge!(
+        "storage_pool_idle_connections",
+        "Open connections waiting in the pool."
+    )
+    .unwrap();
+    pub static ref POOL_IN_USE: IntGauge = register_int_gauge!(
+        "storage_pool_in_use_connections",
+        "Connections currently checked out of the pool."
+    )
+    .unwrap();
+
+    pub static ref POSTS: IntGaugeVec = register_int_gauge_vec!(
+        "posts",
+        "Number of posts by status.",
+        &["status"]
+    )
+    .unwrap();
+}
+
+/// Times a single storage operation and records it under its outcome.
+pub struct Timer {
+    operation: &'static str,
+    start: Instant,
+}
+
+impl Timer {
+    pub fn start(operation: &'static str) -> Self {
+        Self { operation, start: Instant::now() }
+    }
+
+    pub fn finish<T, E>(self, result: &Result<T, E>) {
+        self.record(if result.is_ok() { "ok" } else { "error" });
+    }
+
+    /// Like `finish`, but a lookup that found nothing is recorded as
+    /// `not_found` rather than `ok`.
+    pub fn finish_lookup<T, E>(self, result: &Result<Option<T>, E>) {
+        self.record(match result {
+            Ok(Some(_)) => "ok",
+            Ok(None) => "not_found",
+            Err(_) => "error",
+        });
+    }
+
+    fn record(self, outcome: &str) {
+        OP_TOTAL
+            .with_label_values(&[BACKEND, self.operation, outcome])
+            .inc();
+        OP_LATENCY
+            .with_label_values(&[BACKEND, self.operation, outcome])
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
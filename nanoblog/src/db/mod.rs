@@ -1,20 +1,144 @@
 #![allow(clippy::needless_lifetimes, dead_code)]
 use std::collections::HashMap;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use chrono::{Local, Utc};
 use r2d2_redis::redis;
 use r2d2_redis::redis::Commands;
 
 pub mod types;
 pub mod conn_pool;
+pub mod metrics;
 
 pub use conn_pool::Database;
 pub use types::*;
 
+use crate::cache;
+use metrics::Timer;
+
+/// List of a post's `PostRevision`s. Slugs never contain a colon, so this
+/// can't collide with a post key.
+fn revision_key(slug: &str) -> String {
+    format!("post_revisions:{}", slug)
+}
+
+/// Set once `migrate` has run, after which `post_slugs` lists every post.
+const MIGRATED: &str = "post_slugs_complete";
+
+/// Keys `migrate` asks `SCAN` for at a time.
+const MIGRATE_SCAN_COUNT: usize = 500;
+
+/// Uploaded assets' metadata, keyed by hash. Posts are stored under their
+/// slug, and slugs never contain an underscore, so no post can replace it.
+const ASSET_META: &str = "asset_meta";
+
+/// Bumped whenever the index listing changes.
+const INDEX_REVISION: &str = "index_revision";
+/// When the index listing last changed, as RFC 3339.
+const INDEX_UPDATED_AT: &str = "index_updated_at";
+
+/// Last sequence number handed out to the change feed.
+const CHANGE_SEQ: &str = "change_seq";
+/// The change feed: JSON `Change`s scored by their sequence number.
+const CHANGE_LOG: &str = "change_log";
+
+/// Changes kept in the feed, set from `changes.log_size`.
+static CHANGE_LOG_SIZE: AtomicUsize = AtomicUsize::new(10_000);
+
+/// Set the number of changes the feed keeps.
+pub fn set_change_log_size(changes: usize) {
+    CHANGE_LOG_SIZE.store(changes, Ordering::SeqCst);
+}
+
+/// Number a change and add it to the feed, dropping the oldest entries past
+/// the configured size. Numbering happens in Redis so that changes from
+/// every server share one sequence.
+const RECORD_CHANGE: &str = r"
+local seq = redis.call('INCR', KEYS[1])
+local change = cjson.encode({seq = seq, kind = ARGV[1], id = ARGV[2], at = ARGV[3]})
+redis.call('ZADD', KEYS[2], seq, change)
+redis.call('ZREMRANGEBYRANK', KEYS[2], 0, -1 - tonumber(ARGV[4]))
+return seq
+";
+
+/// Mark the index listing as changed, in the same transaction as the change.
+fn bump_index(pipe: &mut redis::Pipeline) {
+    pipe.incr(INDEX_REVISION, 1).ignore()
+        .set(INDEX_UPDATED_AT, Utc::now().to_rfc3339()).ignore();
+}
+
+/// Add a change feed entry to a pipeline, so it's written in the same
+/// transaction as the change itself.
+fn record_change(pipe: &mut redis::Pipeline, kind: ChangeKind, id: &str) {
+    pipe.cmd("EVAL")
+        .arg(RECORD_CHANGE)
+        .arg(2)
+        .arg(CHANGE_SEQ)
+        .arg(CHANGE_LOG)
+        .arg(kind.as_str())
+        .arg(id)
+        .arg(Utc::now().to_rfc3339())
+        .arg(CHANGE_LOG_SIZE.load(Ordering::SeqCst))
+        .ignore();
+}
+
+/// Changes read from the feed, along with the bounds of what it still holds.
+pub struct ChangeWindow {
+    pub changes: Vec<Change>,
+    /// Sequence number of the oldest change kept, if there are any.
+    pub oldest: Option<u64>,
+    /// Sequence number of the latest change.
+    pub latest: u64,
+}
+
+/// Webhook deliveries waiting to be sent, pushed on the left.
+const WEBHOOK_QUEUE: &str = "webhook_queue";
+/// Deliveries taken off the queue by a worker that hasn't finished them,
+/// scored by when the worker's lease on them runs out.
+const WEBHOOK_INFLIGHT: &str = "webhook_inflight";
+/// Deliveries waiting to be retried, scored by when they're due.
+const WEBHOOK_RETRIES: &str = "webhook_retries";
+/// Every delivery attempt, newest first, trimmed to `webhooks.log_size`.
+const WEBHOOK_LOG: &str = "webhook_deliveries";
+
+/// Move deliveries due by `ARGV[1]` from a sorted set onto the queue in one
+/// step, so a delivery is never in both or neither when several servers share
+/// the queue. Used for due retries and for expired leases.
+const PROMOTE_DUE: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, 100)
+for _, entry in ipairs(due) do
+    redis.call('ZREM', KEYS[1], entry)
+    redis.call('LPUSH', KEYS[2], entry)
+end
+return #due
+";
+
+/// Take the next delivery off the queue, leasing it until `ARGV[1]`.
+const CLAIM_WEBHOOK: &str = r"
+local entry = redis.call('RPOP', KEYS[1])
+if entry then
+    redis.call('ZADD', KEYS[2], ARGV[1], entry)
+end
+return entry
+";
+
+/// Commands storing a post and its bookkeeping, for callers to add to.
+fn save_pipe(post: &Post) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .set(&post.slug, serde_json::to_string(post).unwrap_or_default()).ignore()
+        .sadd("post_slugs", &post.slug).ignore()
+        .hset("post_revisions", &post.slug, post.revision).ignore();
+    bump_index(&mut pipe);
+    record_change(&mut pipe, ChangeKind::PostSaved, &post.slug);
+    pipe
+}
 
 impl Database {
     /// Verify whether the bearer token exists.
     pub async fn validate_token(self, token: String) -> bool {
+        let timer = Timer::start("validate_token");
         let exists = self.run(move |conn| {
             redis::cmd("SISMEMBER")
                 .arg("bearer_tokens")
@@ -22,6 +146,7 @@ impl Database {
                 .query::<bool>(conn.deref())
         })
         .await;
+        timer.finish(&exists);
 
         match exists {
             Err(_) => false,
@@ -29,41 +154,538 @@ impl Database {
         }
     }
 
-    /// Get a `Post`, by its `title`  property.
-    pub async fn get_post(&self, title: String) -> Result<Post, String> {
-        let post = self.run(move |conn| conn.get(title))
-            .await
-            .map_err(|e| e.to_string())?;
-        Ok(post)
+    /// Check whether the bearer token has been granted admin rights.
+    pub async fn is_admin_token(self, token: String) -> bool {
+        let timer = Timer::start("is_admin_token");
+        let exists = self.run(move |conn| {
+            redis::cmd("SISMEMBER")
+                .arg("admin_tokens")
+                .arg(token)
+                .query::<bool>(conn.deref())
+        })
+        .await;
+        timer.finish(&exists);
+
+        exists.unwrap_or(false)
+    }
+
+    /// Get a `Post`, by its `title`  property. `None` if there's no such post.
+    pub async fn get_post(&self, title: String) -> Result<Option<Post>, String> {
+        let timer = Timer::start("get");
+        let post = self.run(move |conn| conn.get(title)).await;
+        timer.finish_lookup(&post);
+        post.map_err(|e| e.to_string())
     }
 
     /// Retrieve the hash mapping post titles to publish dates.
     pub async fn list_posts(&self) -> HashMap<String, PostMetadata> {
-        self.run(move |conn| {
+        let timer = Timer::start("list");
+        let posts = self.run(move |conn| {
             conn.hgetall("posts")
         })
-        .await
-        .unwrap_or_default()
+        .await;
+        timer.finish(&posts);
+        posts.unwrap_or_default()
+    }
+
+    /// Summaries of every indexed post and, with `drafts`, every saved post
+    /// missing from the index too, newest first.
+    pub async fn post_summaries(&self, drafts: bool) -> Result<Vec<PostSummary>, String> {
+        let timer = Timer::start("summaries");
+        let res = self.run(move |conn| {
+            let listed: HashMap<String, PostMetadata> = conn.hgetall("posts")?;
+            let mut unlisted = vec![];
+            if drafts {
+                let slugs: Vec<String> = conn.smembers("post_slugs")?;
+                let slugs: Vec<String> = slugs.into_iter()
+                    .filter(|s| !listed.contains_key(s))
+                    .collect();
+                if !slugs.is_empty() {
+                    let posts: Vec<Option<Post>> = redis::cmd("MGET").arg(&slugs).query(conn.deref())?;
+                    unlisted = posts.into_iter().filter_map(|p| p).collect();
+                }
+            }
+            Ok::<_, redis::RedisError>((listed, unlisted))
+        })
+        .await;
+        timer.finish(&res);
+        let (listed, unlisted) = res.map_err(|e| e.to_string())?;
+
+        let today = Local::today().format("%F").to_string();
+        let mut summaries: Vec<PostSummary> = listed.into_iter()
+            .map(|(_, p)| PostSummary::listed(p, &today))
+            .chain(unlisted.into_iter().map(|p| PostSummary::new(p.into(), PostStatus::Draft)))
+            .collect();
+        summaries.sort_by(|a, b| b.date_created.cmp(&a.date_created).then_with(|| a.slug.cmp(&b.slug)));
+        Ok(summaries)
+    }
+
+    /// Current revision of a post, without fetching the post itself. Posts
+    /// saved before revisions were tracked report `0`, and deleted ones the
+    /// revision after their last.
+    pub async fn get_revision(&self, slug: String) -> u64 {
+        let timer = Timer::start("get_revision");
+        let rev = self.run(move |conn| conn.hget::<_, _, Option<u64>>("post_revisions", slug)).await;
+        timer.finish(&rev);
+        rev.unwrap_or_default().unwrap_or_default()
+    }
+
+    /// Revision of the index listing, bumped whenever a post is saved,
+    /// activated or unpublished, along with when that last happened.
+    pub async fn index_version(&self) -> (u64, Option<String>) {
+        let timer = Timer::start("index_version");
+        let version = self.run(move |conn| {
+            redis::cmd("MGET").arg(INDEX_REVISION).arg(INDEX_UPDATED_AT)
+                .query::<(Option<u64>, Option<String>)>(conn.deref())
+        })
+        .await;
+        timer.finish(&version);
+        let (revision, updated_at) = version.unwrap_or_default();
+        (revision.unwrap_or_default(), updated_at)
+    }
+
+    /// Whether a post is listed on the index.
+    pub async fn is_published(&self, slug: String) -> bool {
+        let timer = Timer::start("is_published");
+        let exists = self.run(move |conn| conn.hexists("posts", slug)).await;
+        timer.finish(&exists);
+        exists.unwrap_or(false)
     }
 
     /// Add a post to the index listing by adding it to the hash map storing active posts
     pub async fn activate_post(&self, post: PostMetadata) -> Result<(), String> {
-        self.run(move |conn| conn.hset("posts", &post.slug, &post))
-            .await
-            .map_err(|e| e.to_string())?;
+        cache::invalidate(&post.slug);
+        let timer = Timer::start("activate");
+        let res = self.run(move |conn| {
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .hset("posts", &post.slug, &post).ignore();
+            bump_index(&mut pipe);
+            record_change(&mut pipe, ChangeKind::PostPublished, &post.slug);
+            pipe.query::<()>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Remove a post from the index listing. The post itself stays reachable
+    /// by its slug, the same as a draft.
+    pub async fn deactivate_post(&self, slug: String) -> Result<(), String> {
+        cache::invalidate(&slug);
+        let timer = Timer::start("deactivate");
+        let res = self.run(move |conn| {
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .hdel("posts", &slug).ignore();
+            bump_index(&mut pipe);
+            record_change(&mut pipe, ChangeKind::PostUnpublished, &slug);
+            pipe.query::<()>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())?;
         Ok(())
     }
 
     /// Save a json serialized version of a `Post` keyed by the post title
     pub async fn save_post(&self, post: Post) -> Result<(), String> {
+        cache::invalidate(&post.slug);
+        let timer = Timer::start("save");
+        let res = self.run(move |conn| {
+            save_pipe(&post).query::<()>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Save a post along with the history entry for the change, atomically,
+    /// provided the stored post is still at revision `expected`. Returns
+    /// false, saving nothing, if another change got there first.
+    pub async fn save_post_revision(&self, post: Post, revision: PostRevision, expected: u64) -> Result<bool, String> {
+        cache::invalidate(&post.slug);
+        let timer = Timer::start("save_revision");
+        let res = self.run(move |conn| -> redis::RedisResult<bool> {
+            loop {
+                // Any write to the post between here and EXEC aborts the
+                // transaction, so the revision compared is the one replaced.
+                redis::cmd("WATCH").arg(&post.slug).query::<()>(conn.deref())?;
+                let stored: Option<String> = conn.get(&post.slug)?;
+                let current = stored
+                    .and_then(|s| serde_json::from_str::<Post>(&s).ok())
+                    .map(|p| p.revision);
+                if current != Some(expected) {
+                    redis::cmd("UNWATCH").query::<()>(conn.deref())?;
+                    return Ok(false);
+                }
+                let saved: Option<()> = save_pipe(&post)
+                    .lpush(revision_key(&post.slug), &revision).ignore()
+                    .query(conn.deref())?;
+                if saved.is_some() {
+                    return Ok(true);
+                }
+            }
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())
+    }
+
+    /// A post's history, newest first.
+    pub async fn post_revisions(&self, slug: String) -> Result<Vec<PostRevision>, String> {
+        let timer = Timer::start("revisions");
+        let revisions = self.run(move |conn| conn.lrange(revision_key(&slug), 0, -1)).await;
+        timer.finish(&revisions);
+        revisions.map_err(|e| e.to_string())
+    }
+
+    /// Remove a post entirely, along with its history.
+    pub async fn delete_post(&self, slug: String) -> Result<(), String> {
+        self.apply_changes(vec![PostChange::Delete(slug)]).await
+    }
+
+    /// Apply several changes as a single transaction: either all of them
+    /// take effect or none do.
+    pub async fn apply_changes(&self, changes: Vec<PostChange>) -> Result<(), String> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        for change in &changes {
+            cache::invalidate(change.slug());
+        }
+        let timer = Timer::start("apply_changes");
+        let res = self.run(move |conn| {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for change in &changes {
+                match change {
+                    PostChange::Save(post) => {
+                        pipe.set(&post.slug, serde_json::to_string(post).unwrap_or_default()).ignore()
+                            .sadd("post_slugs", &post.slug).ignore()
+                            .hset("post_revisions", &post.slug, post.revision).ignore();
+                        record_change(&mut pipe, ChangeKind::PostSaved, &post.slug);
+                    },
+                    PostChange::Activate(post) => {
+                        pipe.hset("posts", &post.slug, post).ignore();
+                        record_change(&mut pipe, ChangeKind::PostPublished, &post.slug);
+                    },
+                    PostChange::Deactivate(slug) => {
+                        pipe.hdel("posts", slug).ignore();
+                        record_change(&mut pipe, ChangeKind::PostUnpublished, slug);
+                    },
+                    PostChange::Delete(slug) => {
+                        // The revision counter outlives the post and counts
+                        // the delete, so neither the gone post's cached pages
+                        // nor a post recreated under the slug reuse its ETags.
+                        pipe.del(slug).ignore()
+                            .srem("post_slugs", slug).ignore()
+                            .hdel("posts", slug).ignore()
+                            .hincr("post_revisions", slug, 1).ignore()
+                            .del(revision_key(slug)).ignore();
+                        record_change(&mut pipe, ChangeKind::PostDeleted, slug);
+                    },
+                }
+            }
+            bump_index(&mut pipe);
+            pipe.query::<()>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())
+    }
+
+    /// Up to `limit` changes after the `since` cursor, oldest first.
+    pub async fn changes(&self, since: u64, limit: usize) -> Result<ChangeWindow, String> {
+        let timer = Timer::start("changes");
+        let res = self.run(move |conn| {
+            redis::pipe()
+                .atomic()
+                .zrange(CHANGE_LOG, 0, 0)
+                .get(CHANGE_SEQ)
+                .cmd("ZRANGEBYSCORE")
+                    .arg(CHANGE_LOG)
+                    .arg(format!("({}", since))
+                    .arg("+inf")
+                    .arg("LIMIT")
+                    .arg(0)
+                    .arg(limit)
+                .query::<(Vec<Change>, Option<u64>, Vec<Change>)>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        let (oldest, latest, changes) = res.map_err(|e| e.to_string())?;
+        Ok(ChangeWindow {
+            changes,
+            oldest: oldest.first().map(|c| c.seq),
+            latest: latest.unwrap_or_default(),
+        })
+    }
+
+    /// Queue webhook deliveries for the worker.
+    pub async fn enqueue_webhooks(&self, deliveries: Vec<WebhookDelivery>) -> Result<(), String> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+        let timer = Timer::start("enqueue_webhooks");
+        let res = self.run(move |conn| {
+            let entries: Vec<String> = deliveries.iter()
+                .map(|d| serde_json::to_string(d).unwrap_or_default())
+                .collect();
+            conn.lpush::<_, _, ()>(WEBHOOK_QUEUE, entries)
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())
+    }
+
+    /// Move retries due by `now`, a Unix timestamp, back onto the queue.
+    pub async fn promote_webhook_retries(&self, now: i64) -> Result<usize, String> {
+        let timer = Timer::start("promote_webhook_retries");
+        let res = self.run(move |conn| {
+            redis::Script::new(PROMOTE_DUE)
+                .key(WEBHOOK_RETRIES)
+                .key(WEBHOOK_QUEUE)
+                .arg(now)
+                .invoke::<usize>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())
+    }
+
+    /// Put in-flight deliveries whose lease ran out by `now` back on the
+    /// queue, returning how many there were. The worker holding them is
+    /// taken to have stopped; they may have been sent already, which
+    /// receivers can spot by their repeated delivery ID.
+    pub async fn expire_webhook_leases(&self, now: i64) -> Result<usize, String> {
+        let timer = Timer::start("expire_webhook_leases");
+        let res = self.run(move |conn| {
+            redis::Script::new(PROMOTE_DUE)
+                .key(WEBHOOK_INFLIGHT)
+                .key(WEBHOOK_QUEUE)
+                .arg(now)
+                .invoke::<usize>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())
+    }
+
+    /// Take a queued delivery, if there is one, leasing it to the caller
+    /// until `lease_until`, a Unix timestamp, or `finish_webhook` is called
+    /// with the same entry.
+    pub async fn next_webhook(&self, lease_until: i64) -> Result<Option<String>, String> {
+        let timer = Timer::start("next_webhook");
+        let res = self.run(move |conn| {
+            redis::Script::new(CLAIM_WEBHOOK)
+                .key(WEBHOOK_QUEUE)
+                .key(WEBHOOK_INFLIGHT)
+                .arg(lease_until)
+                .invoke::<Option<String>>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())
+    }
+
+    /// Record an attempt at the in-flight `entry`: log it, then either drop
+    /// it or schedule a retry at `retry_at`, a Unix timestamp.
+    pub async fn finish_webhook(
+        &self,
+        entry: String,
+        delivery: WebhookDelivery,
+        retry_at: Option<i64>,
+        log_size: usize,
+    ) -> Result<(), String> {
+        let timer = Timer::start("finish_webhook");
+        let res = self.run(move |conn| {
+            let updated = serde_json::to_string(&delivery).unwrap_or_default();
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .zrem(WEBHOOK_INFLIGHT, &entry).ignore()
+                .lpush(WEBHOOK_LOG, &updated).ignore()
+                .ltrim(WEBHOOK_LOG, 0, log_size as isize - 1).ignore();
+            if let Some(at) = retry_at {
+                pipe.zadd(WEBHOOK_RETRIES, &updated, at).ignore();
+            }
+            pipe.query::<()>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())
+    }
+
+    /// Drop an in-flight entry that can't be delivered at all.
+    pub async fn discard_webhook(&self, entry: String) -> Result<(), String> {
+        self.run(move |conn| conn.zrem::<_, _, ()>(WEBHOOK_INFLIGHT, entry))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// The delivery log, newest attempt first.
+    pub async fn webhook_deliveries(&self) -> Result<Vec<WebhookDelivery>, String> {
+        let timer = Timer::start("webhook_deliveries");
+        let log = self.run(move |conn| conn.lrange(WEBHOOK_LOG, 0, -1)).await;
+        timer.finish(&log);
+        log.map_err(|e| e.to_string())
+    }
+
+    /// Metadata of an uploaded asset.
+    pub async fn get_asset(&self, hash: String) -> Result<Option<Asset>, String> {
+        let timer = Timer::start("get_asset");
+        let asset = self.run(move |conn| conn.hget(ASSET_META, hash)).await;
+        timer.finish_lookup(&asset);
+        asset.map_err(|e| e.to_string())
+    }
+
+    /// Record an asset's metadata, along with its contents when they're kept
+    /// in Redis rather than on disk.
+    pub async fn save_asset(&self, asset: Asset, data: Option<Vec<u8>>) -> Result<(), String> {
+        let timer = Timer::start("save_asset");
+        let res = self.run(move |conn| {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            if let Some(data) = data {
+                pipe.set(format!("asset:{}", asset.hash), data).ignore();
+            }
+            pipe.hset(ASSET_META, &asset.hash, &asset).ignore();
+            record_change(&mut pipe, ChangeKind::AssetSaved, &asset.hash);
+            pipe.query::<()>(conn.deref())
+        })
+        .await;
+        timer.finish(&res);
+        res.map_err(|e| e.to_string())
+    }
+
+    /// Contents of an asset stored in Redis.
+    pub async fn get_asset_data(&self, hash: String) -> Result<Option<Vec<u8>>, String> {
+        let timer = Timer::start("get_asset_data");
+        let data = self.run(move |conn| conn.get(format!("asset:{}", hash))).await;
+        timer.finish_lookup(&data);
+        data.map_err(|e| e.to_string())
+    }
+
+    /// Slugs of every saved post, published or not.
+    pub async fn all_slugs(&self) -> Result<Vec<String>, String> {
+        let timer = Timer::start("all_slugs");
+        let slugs = self.run(move |conn| conn.smembers("post_slugs")).await;
+        timer.finish(&slugs);
+        slugs.map_err(|e| e.to_string())
+    }
+
+    /// Grant a bearer token access to the API, optionally as an admin.
+    pub async fn add_token(&self, token: String, admin: bool) -> Result<(), String> {
         self.run(move |conn| {
-            conn.set(
-                &post.slug,
-                serde_json::to_string(&post).unwrap_or_default()
-            )
+            let mut pipe = redis::pipe();
+            pipe.atomic().sadd("bearer_tokens", &token).ignore();
+            if admin {
+                pipe.sadd("admin_tokens", &token).ignore();
+            }
+            pipe.query::<()>(conn.deref())
         })
         .await
-        .map_err(|e| e.to_string())?;
-        Ok(())
+        .map_err(|e| e.to_string())
+    }
+
+    /// Revoke a bearer token, returning whether it existed.
+    pub async fn revoke_token(&self, token: String) -> Result<bool, String> {
+        self.run(move |conn| {
+            redis::pipe()
+                .atomic()
+                .srem("bearer_tokens", &token)
+                .srem("admin_tokens", &token).ignore()
+                .query::<(bool,)>(conn.deref())
+        })
+        .await
+        .map(|(existed,)| existed)
+        .map_err(|e| e.to_string())
+    }
+
+    /// Backfill the bookkeeping keys added after posts were first stored:
+    /// the `post_slugs` set and `post_revisions` hash. Post keys aren't
+    /// namespaced, so every string key holding post JSON is picked up,
+    /// walking the keyspace with `SCAN` so Redis keeps serving meanwhile.
+    /// Returns the number of posts found; a post may be counted twice if the
+    /// keyspace is resized during the scan.
+    pub async fn migrate(&self) -> Result<usize, String> {
+        self.run(move |conn| {
+            let mut cursor: u64 = 0;
+            let mut found = 0;
+            loop {
+                let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("COUNT")
+                    .arg(MIGRATE_SCAN_COUNT)
+                    .query(conn.deref())?;
+                for key in keys {
+                    let kind: String = redis::cmd("TYPE").arg(&key).query(conn.deref())?;
+                    if kind != "string" {
+                        continue;
+                    }
+                    let post: Post = match conn.get(&key) {
+                        Ok(p) => p,
+                        Err(_) => continue,
+                    };
+                    if post.slug != key {
+                        continue;
+                    }
+                    redis::pipe()
+                        .sadd("post_slugs", &post.slug).ignore()
+                        .cmd("HSETNX").arg("post_revisions").arg(&post.slug).arg(post.revision).ignore()
+                        .query::<()>(conn.deref())?;
+                    found += 1;
+                }
+                cursor = next;
+                if cursor == 0 {
+                    break;
+                }
+            }
+            conn.set::<_, _, ()>(MIGRATED, 1)?;
+            Ok(found)
+        })
+        .await
+        .map_err(|e: redis::RedisError| e.to_string())
+    }
+
+    /// Whether `migrate` has backfilled storage since posts saved by older
+    /// versions were last around.
+    pub async fn is_migrated(&self) -> Result<bool, String> {
+        let timer = Timer::start("is_migrated");
+        let migrated = self.run(move |conn| conn.exists(MIGRATED)).await;
+        timer.finish(&migrated);
+        migrated.map_err(|e| e.to_string())
+    }
+
+    /// Refresh the published, draft and scheduled post gauges. Drafts are
+    /// saved posts missing from the index, and scheduled posts are published
+    /// ones dated in the future. Until `migrate` has run, `post_slugs` may be
+    /// missing older posts, so no draft count is reported.
+    pub async fn record_post_counts(&self) {
+        let timer = Timer::start("count");
+        let counts = self.run(move |conn| {
+            let saved: i64 = conn.scard("post_slugs")?;
+            let published: HashMap<String, PostMetadata> = conn.hgetall("posts")?;
+            let migrated: bool = conn.exists(MIGRATED)?;
+            Ok::<_, redis::RedisError>((saved, published, migrated))
+        })
+        .await;
+        timer.finish(&counts);
+
+        if let Ok((saved, published, migrated)) = counts {
+            let today = Local::today().format("%F").to_string();
+            let scheduled = published.values()
+                .filter(|p| p.date_created > today)
+                .count() as i64;
+            let published = published.len() as i64;
+
+            metrics::POSTS.with_label_values(&["published"]).set(published - scheduled);
+            metrics::POSTS.with_label_values(&["scheduled"]).set(scheduled);
+            if migrated {
+                metrics::POSTS.with_label_values(&["draft"]).set((saved - published).max(0));
+            }
+        }
     }
 }
@@ -1,5 +1,6 @@
-use chrono::Local;
+use chrono::{Local, Utc};
 use slug::slugify;
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 use r2d2_redis::redis::{
     Value,
@@ -10,19 +11,78 @@ use r2d2_redis::redis::{
     ErrorKind,
 };
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use crate::error::ApiError;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct NewPost {
     pub title: String,
+    /// Markdown, optionally preceded by a `+++` TOML front matter block.
     pub body: String,
+    /// Skip HTML sanitization when rendering. Only admin tokens may set this.
+    #[serde(default)]
+    pub trusted_html: bool,
+    /// Replaces the post's tags. Left out, an existing post keeps its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The fields a JSON merge patch (RFC 7396) may change. `api::patch_post`
+/// merges the patch into the stored post as JSON, so this only describes it.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PostPatch {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// `null` clears them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trusted_html: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_created: Option<String>,
+    /// Publishes the post, or with `true` unpublishes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub draft: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Post {
     pub slug: String,
     pub title: String,
     pub body: String,
     pub date_created: String,
     pub date_updated: Option<String>,
+    /// When the post was last stored, as RFC 3339. Sent as `Last-Modified`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    #[serde(default)]
+    pub trusted_html: bool,
+    /// Bumped on every save, used to key rendered page caches.
+    #[serde(default)]
+    pub revision: u64,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Post {
+    /// Record that the post is being stored now.
+    pub fn touch(&mut self) {
+        self.updated_at = Some(Utc::now().to_rfc3339());
+    }
+}
+
+/// Trim tags, dropping empty and repeated ones while keeping their order.
+pub fn clean_tags(tags: Vec<String>) -> Vec<String> {
+    let mut cleaned: Vec<String> = vec![];
+    for tag in tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !cleaned.iter().any(|t| t == tag) {
+            cleaned.push(tag.to_string());
+        }
+    }
+    cleaned
 }
 
 impl From<NewPost> for Post {
@@ -33,6 +93,10 @@ impl From<NewPost> for Post {
             body: post.body,
             date_created: Local::today().format("%F").to_string(),
             date_updated: None,
+            updated_at: None,
+            trusted_html: post.trusted_html,
+            revision: 0,
+            tags: clean_tags(post.tags.unwrap_or_default()),
         }
     }
 }
@@ -44,17 +108,83 @@ impl Default for Post {
             title: "".into(),
             body: "".into(),
             date_created: "".into(),
-            date_updated: None
+            date_updated: None,
+            updated_at: None,
+            trusted_html: false,
+            revision: 0,
+            tags: vec![],
         }
     }
 }
 
+/// A post as written by `nanoblog export`, and read back by its `import` and
+/// by `postctl import`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedPost {
+    #[serde(flatten)]
+    pub post: Post,
+    pub published: bool,
+}
+
+/// An uploaded file, addressed by the SHA-256 of its contents. The same
+/// contents uploaded under another name share one stored copy.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Asset {
+    /// Hex SHA-256 of the contents.
+    pub hash: String,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    /// Intrinsic dimensions, for images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Resized and WebP copies of an image, each stored as its own asset.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+}
+
+/// A processed copy of an image asset.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Variant {
+    pub hash: String,
+    pub name: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Variant {
+    pub fn url(&self) -> String {
+        format!("/assets/{}/{}", self.hash, self.name)
+    }
+}
+
+impl Asset {
+    /// Public path the asset is served from.
+    pub fn url(&self) -> String {
+        format!("/assets/{}/{}", self.hash, self.name)
+    }
+}
+
+/// Access level granted by a bearer token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenRole {
+    Author,
+    Admin,
+}
+
 /// Listing for the index page
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PostMetadata {
     pub slug: String,
     pub title: String,
     pub date_created: String,
+    #[serde(default)]
+    pub date_updated: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 /// Convert a `Post` into its `PostMetadata`
@@ -64,10 +194,323 @@ impl From<Post> for PostMetadata {
             slug: post.slug,
             title: post.title,
             date_created: post.date_created,
+            date_updated: post.date_updated,
+            tags: post.tags,
+        }
+    }
+}
+
+/// A storage operation on a post, for applying several at once with
+/// `Database::apply_changes`.
+#[derive(Debug, Clone)]
+pub enum PostChange {
+    Save(Post),
+    /// Add or refresh the post's index entry.
+    Activate(PostMetadata),
+    Deactivate(String),
+    Delete(String),
+}
+
+impl PostChange {
+    pub fn slug(&self) -> &str {
+        match self {
+            PostChange::Save(post) => &post.slug,
+            PostChange::Activate(post) => &post.slug,
+            PostChange::Deactivate(slug) | PostChange::Delete(slug) => slug,
+        }
+    }
+}
+
+/// Whether a batch is applied all-or-nothing or item by item.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    Atomic,
+    BestEffort,
+}
+
+impl Default for BatchMode {
+    fn default() -> Self {
+        BatchMode::Atomic
+    }
+}
+
+/// One item of a batch, tagged by `op`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOperation {
+    /// Create or replace a post, publishing it unless `draft` is set.
+    Upsert {
+        post: NewPost,
+        #[serde(default)]
+        draft: bool,
+        /// Keep a post's original date, e.g. when importing it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        date_created: Option<String>,
+        /// Store the post under this slug rather than its title's, e.g. when
+        /// importing a post retitled since it was created.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        slug: Option<String>,
+    },
+    Publish { slug: String },
+    Unpublish { slug: String },
+    Delete { slug: String },
+}
+
+impl BatchOperation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BatchOperation::Upsert { .. } => "upsert",
+            BatchOperation::Publish { .. } => "publish",
+            BatchOperation::Unpublish { .. } => "unpublish",
+            BatchOperation::Delete { .. } => "delete",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct BatchRequest {
+    #[serde(default)]
+    pub mode: BatchMode,
+    pub operations: Vec<BatchOperation>,
+}
+
+/// The outcome of one batch item, in the same order as the request.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct BatchItemResult {
+    pub op: String,
+    pub slug: String,
+    /// The HTTP status the operation would have had on its own.
+    pub status: u16,
+    /// The post as saved, for upserts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post: Option<Post>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiError>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct BatchResponse {
+    pub mode: BatchMode,
+    /// Whether the batch's changes were stored. Best-effort batches always
+    /// are, item by item; atomic ones only if every item succeeded.
+    pub committed: bool,
+    pub results: Vec<BatchItemResult>,
+}
+
+/// One entry in a post's history, recorded by each partial update.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct PostRevision {
+    /// The post's revision after the change.
+    pub revision: u64,
+    pub date: String,
+    /// The merge patch that produced this revision.
+    pub changes: serde_json::Value,
+}
+
+/// Whether, and since when, a post is visible on the index.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostStatus {
+    Published,
+    /// On the index, but dated in the future.
+    Scheduled,
+    /// Saved but never published, or unpublished since.
+    Draft,
+}
+
+impl PostStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PostStatus::Published => "published",
+            PostStatus::Scheduled => "scheduled",
+            PostStatus::Draft => "draft",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "published" => Some(PostStatus::Published),
+            "scheduled" => Some(PostStatus::Scheduled),
+            "draft" => Some(PostStatus::Draft),
+            _ => None,
+        }
+    }
+}
+
+/// One entry of the API's post listing, which is ordered newest first.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct PostSummary {
+    pub slug: String,
+    pub title: String,
+    pub date_created: String,
+    pub date_updated: Option<String>,
+    pub status: PostStatus,
+    pub tags: Vec<String>,
+}
+
+impl PostSummary {
+    /// Summarize an indexed post, which is scheduled rather than published
+    /// while it's dated after `today`.
+    pub fn listed(post: PostMetadata, today: &str) -> Self {
+        let status = if post.date_created.as_str() > today {
+            PostStatus::Scheduled
+        } else {
+            PostStatus::Published
+        };
+        Self::new(post, status)
+    }
+
+    pub fn new(post: PostMetadata, status: PostStatus) -> Self {
+        Self {
+            slug: post.slug,
+            title: post.title,
+            date_created: post.date_created,
+            date_updated: post.date_updated,
+            status,
+            tags: post.tags,
         }
     }
 }
 
+/// A content change that webhook subscriptions can ask to be told about.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+pub enum WebhookEvent {
+    #[serde(rename = "post.created")]
+    PostCreated,
+    #[serde(rename = "post.updated")]
+    PostUpdated,
+    /// Added to the index, either new or after being unpublished.
+    #[serde(rename = "post.published")]
+    PostPublished,
+    #[serde(rename = "post.unpublished")]
+    PostUnpublished,
+    #[serde(rename = "post.deleted")]
+    PostDeleted,
+}
+
+impl WebhookEvent {
+    pub const ALL: &'static [WebhookEvent] = &[
+        WebhookEvent::PostCreated,
+        WebhookEvent::PostUpdated,
+        WebhookEvent::PostPublished,
+        WebhookEvent::PostUnpublished,
+        WebhookEvent::PostDeleted,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::PostCreated => "post.created",
+            WebhookEvent::PostUpdated => "post.updated",
+            WebhookEvent::PostPublished => "post.published",
+            WebhookEvent::PostUnpublished => "post.unpublished",
+            WebhookEvent::PostDeleted => "post.deleted",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Queued, or waiting to be retried.
+    Pending,
+    Delivered,
+    /// Gave up after the last attempt.
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "pending" => Some(DeliveryStatus::Pending),
+            "delivered" => Some(DeliveryStatus::Delivered),
+            "failed" => Some(DeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// One event sent to one subscription, along with how sending it went so far.
+/// The same record travels through the queue and into the delivery log.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id: String,
+    /// Name of the subscription it's sent to.
+    pub subscription: String,
+    pub event: WebhookEvent,
+    /// The JSON body, signed and sent unchanged on every attempt.
+    pub payload: serde_json::Value,
+    pub created_at: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub last_attempt_at: Option<String>,
+    /// HTTP status of the last response, if there was one.
+    pub response_status: Option<u16>,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    pub next_attempt_at: Option<String>,
+}
+
+/// What a change feed entry records.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    /// A post was created or its contents changed.
+    #[serde(rename = "post.saved")]
+    PostSaved,
+    /// A post was added to the index, or its index entry refreshed.
+    #[serde(rename = "post.published")]
+    PostPublished,
+    #[serde(rename = "post.unpublished")]
+    PostUnpublished,
+    #[serde(rename = "post.deleted")]
+    PostDeleted,
+    #[serde(rename = "asset.saved")]
+    AssetSaved,
+}
+
+impl ChangeKind {
+    pub const ALL: &'static [ChangeKind] = &[
+        ChangeKind::PostSaved,
+        ChangeKind::PostPublished,
+        ChangeKind::PostUnpublished,
+        ChangeKind::PostDeleted,
+        ChangeKind::AssetSaved,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::PostSaved => "post.saved",
+            ChangeKind::PostPublished => "post.published",
+            ChangeKind::PostUnpublished => "post.unpublished",
+            ChangeKind::PostDeleted => "post.deleted",
+            ChangeKind::AssetSaved => "asset.saved",
+        }
+    }
+}
+
+/// One entry of the change feed, written along with every storage call that
+/// changes a post or asset.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Change {
+    /// Position in the feed, increasing by one with every change.
+    pub seq: u64,
+    pub kind: ChangeKind,
+    /// The post's slug, or the asset's hash.
+    pub id: String,
+    pub at: String,
+}
+
+/// A page of the change feed.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ChangeList {
+    /// Oldest first.
+    pub changes: Vec<Change>,
+    /// Cursor to pass as `since` for the changes after these.
+    pub next: u64,
+    /// Whether there are changes past `next` already.
+    pub more: bool,
+}
+
 impl FromRedisValue for PostMetadata {
     fn from_redis_value(v: &Value) -> RedisResult<Self> {
         if let Value::Data(ref bytes) = *v {
@@ -101,3 +544,71 @@ impl ToRedisArgs for &PostMetadata {
         );
     }
 }
+
+impl FromRedisValue for PostRevision {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        if let Value::Data(ref bytes) = *v {
+            let val = serde_json::from_slice::<Self>(bytes)
+                .map_err(|e|
+                    RedisError::from((ErrorKind::TypeError, "PostRevision", e.to_string()))
+                )?;
+            return Ok(val);
+        }
+        Err(RedisError::from((ErrorKind::TypeError, "wasnt passed bytes")))
+    }
+}
+
+impl ToRedisArgs for &PostRevision {
+    fn write_redis_args(&self, out: &mut Vec<Vec<u8>>) {
+        out.push(
+            serde_json::to_vec(self).expect("Couldn't serialize PostRevision")
+        );
+    }
+}
+
+impl FromRedisValue for Asset {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        if let Value::Data(ref bytes) = *v {
+            let val = serde_json::from_slice::<Self>(bytes)
+                .map_err(|e|
+                    RedisError::from((ErrorKind::TypeError, "Asset", e.to_string()))
+                )?;
+            return Ok(val);
+        }
+        Err(RedisError::from((ErrorKind::TypeError, "wasnt passed bytes")))
+    }
+}
+
+impl ToRedisArgs for &Asset {
+    fn write_redis_args(&self, out: &mut Vec<Vec<u8>>) {
+        out.push(
+            serde_json::to_vec(self).expect("Couldn't serialize Asset")
+        );
+    }
+}
+
+impl FromRedisValue for WebhookDelivery {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        if let Value::Data(ref bytes) = *v {
+            let val = serde_json::from_slice::<Self>(bytes)
+                .map_err(|e|
+                    RedisError::from((ErrorKind::TypeError, "WebhookDelivery", e.to_string()))
+                )?;
+            return Ok(val);
+        }
+        Err(RedisError::from((ErrorKind::TypeError, "wasnt passed bytes")))
+    }
+}
+
+impl FromRedisValue for Change {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        if let Value::Data(ref bytes) = *v {
+            let val = serde_json::from_slice::<Self>(bytes)
+                .map_err(|e|
+                    RedisError::from((ErrorKind::TypeError, "Change", e.to_string()))
+                )?;
+            return Ok(val);
+        }
+        Err(RedisError::from((ErrorKind::TypeError, "wasnt passed bytes")))
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use tide::http;
+use http::status::StatusCode;
+
+/// Machine-readable reason for an API failure. Each maps to one HTTP status.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Malformed parameters.
+    BadRequest,
+    /// A request body that doesn't deserialize or fails validation.
+    InvalidBody,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    /// The request conflicts with the current state, e.g. a slug taken by
+    /// another post.
+    Conflict,
+    /// History the request asks for is no longer kept, e.g. a change feed
+    /// cursor older than the oldest change.
+    Gone,
+    /// A conditional request whose precondition doesn't hold.
+    PreconditionFailed,
+    PayloadTooLarge,
+    /// The client used up its rate limit. `Retry-After` says when to retry.
+    TooManyRequests,
+    /// A request body in a format the endpoint doesn't accept.
+    UnsupportedMediaType,
+    /// None of the representations the client accepts are available.
+    NotAcceptable,
+    Internal,
+    /// The storage backend couldn't be reached.
+    StorageUnavailable,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::InvalidBody => "invalid_body",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::Gone => "gone",
+            ErrorCode::PreconditionFailed => "precondition_failed",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::TooManyRequests => "too_many_requests",
+            ErrorCode::UnsupportedMediaType => "unsupported_media_type",
+            ErrorCode::NotAcceptable => "not_acceptable",
+            ErrorCode::Internal => "internal",
+            ErrorCode::StorageUnavailable => "storage_unavailable",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest | ErrorCode::InvalidBody => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::Gone => StatusCode::GONE,
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::StorageUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Body of every failed API response.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Extra context specific to the code, e.g. the field that failed to
+    /// deserialize.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        Self { code, message: message.into(), details: None }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn bad_request<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorCode::BadRequest, message)
+    }
+
+    /// A body that couldn't be deserialized, with the parser's complaint as
+    /// details.
+    pub fn invalid_body<E: fmt::Display>(e: E) -> Self {
+        Self::new(ErrorCode::InvalidBody, "Invalid request body")
+            .with_details(serde_json::json!({ "error": e.to_string() }))
+    }
+
+    pub fn not_found<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn forbidden<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorCode::Forbidden, message)
+    }
+
+    pub fn internal<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    /// A failed storage operation. The backend's error is kept in details
+    /// rather than the message, which stays stable.
+    pub fn storage<E: fmt::Display>(e: E) -> Self {
+        Self::new(ErrorCode::StorageUnavailable, "Storage backend unavailable")
+            .with_details(serde_json::json!({ "error": e.to_string() }))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    pub fn into_response(self) -> http::Response<http_service::Body> {
+        let body = serde_json::to_string(&self).unwrap_or_default();
+        http::Response::builder()
+            .status(self.status())
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(format!("{}\n", body).into())
+            .expect("Error building error response")
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code.as_str())?;
+        if let Some(details) = &self.details {
+            write!(f, ": {}", details)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<ApiError> for tide::Error {
+    fn from(e: ApiError) -> Self {
+        tide::Error::from(e.into_response())
+    }
+}
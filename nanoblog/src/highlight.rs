@@ -0,0 +1,79 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use lru::LruCache;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{css_for_theme, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+use crate::config;
+
+const CACHE_SIZE: usize = 512;
+
+lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref THEMES: ThemeSet = ThemeSet::load_defaults();
+
+    /// Stylesheet for the configured `render.highlight_theme`, generated once at startup.
+    pub static ref STYLESHEET: String = {
+        let name = &config::get().render.highlight_theme;
+        let theme = THEMES.themes.get(name)
+            .expect("Highlight theme was not validated");
+        css_for_theme(theme)
+    };
+
+    /// Highlighted blocks keyed by a hash of their language and contents, so
+    /// that re-rendering a post doesn't re-run the syntax parser.
+    static ref CACHE: Mutex<LruCache<u64, String>> = Mutex::new(LruCache::new(CACHE_SIZE));
+}
+
+/// Whether syntect bundles a theme with this name.
+pub fn theme_exists(name: &str) -> bool {
+    THEMES.themes.contains_key(name)
+}
+
+fn cache_key(lang: &str, code: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    lang.hash(&mut hasher);
+    code.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Highlight a fenced code block into class-based HTML, using the first word
+/// of the fence info string as the language token.
+pub fn highlight(info: &str, code: &str) -> String {
+    let lang = info.split_whitespace().next().unwrap_or_default();
+    // The token ends up in a class attribute, so anything unusual is dropped.
+    let lang = if lang.chars().all(|c| c.is_ascii_alphanumeric() || "+-#_".contains(c)) {
+        lang
+    } else {
+        ""
+    };
+    let key = cache_key(lang, code);
+
+    if let Some(html) = CACHE.lock().unwrap().get(&key) {
+        return html.clone();
+    }
+
+    let syntax = SYNTAXES.find_syntax_by_token(lang)
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+    let mut generator = ClassedHTMLGenerator::new(syntax, &SYNTAXES);
+    for line in LinesWithEndings::from(code) {
+        generator.parse_html_for_line(line);
+    }
+
+    let html = if lang.is_empty() {
+        format!("<pre class=\"code\"><code>{}</code></pre>\n", generator.finalize())
+    } else {
+        format!(
+            "<pre class=\"code\"><code class=\"language-{}\">{}</code></pre>\n",
+            lang,
+            generator.finalize()
+        )
+    };
+
+    CACHE.lock().unwrap().put(key, html.clone());
+    html
+}
@@ -0,0 +1,179 @@
+use chrono::{Date, DateTime, Local, Utc};
+use tide::{http, Response};
+use http::header::{self, HeaderMap, HeaderValue};
+
+use crate::config;
+use crate::templates;
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Groups of routes sharing a `Cache-Control` policy.
+#[derive(Debug, Clone, Copy)]
+pub enum RouteClass {
+    Index,
+    Post,
+    #[allow(dead_code)]
+    Feed,
+    Api,
+}
+
+impl RouteClass {
+    /// `Cache-Control` value for the class, from `cache.cache_control`.
+    pub fn cache_control(self) -> String {
+        let cc = &config::get().cache.cache_control;
+        match self {
+            RouteClass::Index => cc.index.clone(),
+            RouteClass::Post => cc.post.clone(),
+            RouteClass::Feed => cc.feed.clone(),
+            RouteClass::Api => cc.api.clone(),
+        }
+    }
+}
+
+/// Validators describing one version of a resource.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    /// Validators for a rendered page: the revision is combined with the
+    /// template version since either changing alters the output. `updated_at`
+    /// is when the content was last stored, as RFC 3339.
+    pub fn page(revision: u64, updated_at: Option<&str>) -> Self {
+        Self {
+            etag: format!("\"{:x}-{}\"", revision, templates::version()),
+            last_modified: parse_time(updated_at).map(|t| t.max(templates::loaded_at())),
+        }
+    }
+
+    /// Validators for a raw API representation, which templates don't affect.
+    pub fn api(revision: u64, updated_at: Option<&str>) -> Self {
+        Self {
+            etag: format!("\"{:x}\"", revision),
+            last_modified: parse_time(updated_at),
+        }
+    }
+
+    /// The validators of a listing that also changes with the date, as
+    /// scheduled posts go live when theirs arrives.
+    pub fn as_of(mut self, today: Date<Local>) -> Self {
+        self.etag = format!("{}-{}\"", self.etag.trim_end_matches('"'), today.format("%F"));
+        let midnight = today.and_hms(0, 0, 0).with_timezone(&Utc);
+        self.last_modified = self.last_modified.map(|lm| lm.max(midnight));
+        self
+    }
+}
+
+fn parse_time(time: Option<&str>) -> Option<DateTime<Utc>> {
+    time.and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Content codings `Compression` marks ETags with, so that each encoding of
+/// a representation has a tag of its own. Keep in sync with
+/// `middleware::Encoding`.
+const CODINGS: &[&str] = &["br", "gzip", "deflate"];
+
+/// The ETag of a representation sent with the `coding` content encoding:
+/// `"abc"` becomes `"abc-gzip"`.
+pub fn encoded_etag(etag: &str, coding: &str) -> String {
+    match etag.rfind('"') {
+        Some(end) if end > 0 => format!("{}-{}{}", &etag[..end], coding, &etag[end..]),
+        _ => etag.to_string(),
+    }
+}
+
+/// A tag sent back by a client, without the coding `encoded_etag` added, so
+/// that it compares equal to the tag handlers work with.
+fn unencoded(tag: &str) -> String {
+    for coding in CODINGS {
+        let suffix = format!("-{}\"", coding);
+        if tag.ends_with(&suffix) {
+            return format!("{}\"", &tag[..tag.len() - suffix.len()]);
+        }
+    }
+    tag.to_string()
+}
+
+fn etag_matches(header: &HeaderValue, etag: &str) -> bool {
+    let header = match header.to_str() {
+        Ok(h) => h,
+        Err(_) => return false,
+    };
+    // If-None-Match uses the weak comparison function.
+    let etag = etag.trim_start_matches("W/");
+    header.split(',')
+        .map(|t| t.trim())
+        .any(|t| t == "*" || unencoded(t.trim_start_matches("W/")) == etag)
+}
+
+/// Whether the resource is unchanged since the `If-Modified-Since` date.
+/// HTTP dates have whole seconds, so anything finer is ignored.
+fn unmodified_since(header: &HeaderValue, last_modified: &DateTime<Utc>) -> bool {
+    match header.to_str().ok().and_then(|h| DateTime::parse_from_rfc2822(h).ok()) {
+        Some(since) => last_modified.timestamp() <= since.timestamp(),
+        None => false,
+    }
+}
+
+/// Whether the client's cached copy is still current. `If-None-Match` takes
+/// precedence over `If-Modified-Since` when both are sent.
+pub fn is_fresh(headers: &HeaderMap, v: &Validators) -> bool {
+    if let Some(inm) = headers.get(header::IF_NONE_MATCH) {
+        return etag_matches(inm, &v.etag);
+    }
+    match (headers.get(header::IF_MODIFIED_SINCE), &v.last_modified) {
+        (Some(ims), Some(lm)) => unmodified_since(ims, lm),
+        _ => false,
+    }
+}
+
+/// Whether a request's `If-Match` precondition holds for the current
+/// version. It uses the strong comparison function, so weak tags never match.
+pub fn if_match(headers: &HeaderMap, v: &Validators) -> bool {
+    let header = match headers.get(header::IF_MATCH) {
+        Some(h) => h,
+        None => return true,
+    };
+    match header.to_str() {
+        Ok(h) => h.split(',')
+            .map(|t| t.trim())
+            .any(|t| t == "*" || (!t.starts_with("W/") && unencoded(t) == v.etag)),
+        Err(_) => false,
+    }
+}
+
+/// A `304` standing in for `res`. It keeps the content type, which
+/// `Compression` goes by to send the same `Vary` and ETag as `res` would get.
+fn not_modified(res: &Response) -> Response {
+    let mut not_modified = http::Response::builder()
+        .status(http::StatusCode::NOT_MODIFIED)
+        .body(Vec::new().into())
+        .expect("Error building response");
+    if let Some(ct) = res.headers().get(header::CONTENT_TYPE) {
+        not_modified.headers_mut().insert(header::CONTENT_TYPE, ct.clone());
+    }
+    not_modified
+}
+
+/// Attach caching headers to a response, replacing it with a `304 Not
+/// Modified` if the request's conditional headers show it is unchanged.
+pub fn respond(req: &HeaderMap, class: RouteClass, v: &Validators, res: Response) -> Response {
+    let mut res = if is_fresh(req, v) { not_modified(&res) } else { res };
+
+    let headers = res.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&v.etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Some(lm) = v.last_modified {
+        if let Ok(lm) = HeaderValue::from_str(&lm.format(HTTP_DATE).to_string()) {
+            headers.insert(header::LAST_MODIFIED, lm);
+        }
+    }
+    if let Ok(cc) = HeaderValue::from_str(&class.cache_control()) {
+        headers.insert(header::CACHE_CONTROL, cc);
+    }
+    res
+}
@@ -0,0 +1,249 @@
+use futures01::Async;
+use futures01::future::poll_fn;
+use image::{DynamicImage, FilterType, GenericImageView, ImageFormat, ImageOutputFormat};
+use tokio_threadpool::blocking;
+
+const JPEG_QUALITY: u8 = 85;
+const WEBP_QUALITY: f32 = 75.0;
+
+/// JPEG segments dropped from uploads: APP1 holds EXIF and XMP, APP13 IPTC.
+/// The ICC profile in APP2 is kept since colours depend on it.
+const JPEG_METADATA: &[u8] = &[0xE1, 0xED];
+/// PNG chunks dropped from uploads.
+const PNG_METADATA: &[&[u8; 4]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt"];
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Start of image marker, followed by the first segment's marker.
+const JPEG_SIGNATURE: &[u8] = &[0xFF, 0xD8, 0xFF];
+
+/// An encoded image: the upload itself or a variant of it.
+pub struct Encoded {
+    pub data: Vec<u8>,
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub struct Processed {
+    /// The upload with its metadata removed.
+    pub original: Encoded,
+    pub variants: Vec<Encoded>,
+}
+
+/// The type an upload is processed as, if it's an image that gets processed.
+/// The contents' signature decides, so a JPEG or PNG has its metadata
+/// stripped whatever type it was sent as; one sent as either that doesn't
+/// start like it is still treated as such, and fails to decode.
+pub fn supported_type(content_type: &str, data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(JPEG_SIGNATURE) {
+        return Some("image/jpeg");
+    }
+    if data.starts_with(PNG_SIGNATURE) {
+        return Some("image/png");
+    }
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    match media_type.as_str() {
+        "image/jpeg" => Some("image/jpeg"),
+        "image/png" => Some("image/png"),
+        _ => None,
+    }
+}
+
+/// Process an image on the blocking pool, or inline outside of one.
+pub async fn process(data: Vec<u8>, content_type: String, widths: Vec<u32>, webp: bool) -> Result<Processed, String> {
+    use futures::compat::Future01CompatExt;
+    let mut work = Some(move || process_sync(&data, &content_type, &widths, webp));
+    poll_fn(|| {
+        match blocking(|| (work.take().unwrap())()) {
+            Err(_) if work.is_some() => Ok(Async::Ready((work.take().unwrap())())),
+            res => res.map_err(|_| panic!("the threadpool shut down")),
+        }
+    })
+    .compat()
+    .await
+    .expect("Error running image processing task.")
+}
+
+/// Strip metadata from an image, and produce a resized copy for every
+/// configured width narrower than it, plus WebP versions of each.
+pub fn process_sync(data: &[u8], content_type: &str, widths: &[u32], webp: bool) -> Result<Processed, String> {
+    let (format, stripped, orientation) = match content_type {
+        "image/jpeg" => {
+            let (stripped, orientation) = strip_jpeg(data)?;
+            (ImageFormat::JPEG, stripped, orientation)
+        },
+        "image/png" => (ImageFormat::PNG, strip_png(data)?, 1),
+        _ => return Err(format!("Can't process {} images", content_type)),
+    };
+
+    let img = image::load_from_memory_with_format(&stripped, format).map_err(|e| e.to_string())?;
+    let img = orient(img, orientation);
+
+    // Rotating means re-encoding, otherwise the upload is kept as it was.
+    let original = if orientation == 1 {
+        Encoded { data: stripped, content_type: mime(format), width: img.width(), height: img.height() }
+    } else {
+        encode(&img, format)?
+    };
+
+    let mut variants = vec![];
+    let mut widths: Vec<u32> = widths.iter().cloned().filter(|w| *w < img.width()).collect();
+    widths.sort();
+    widths.dedup();
+    for width in widths {
+        let resized = img.resize(width, img.height(), FilterType::Lanczos3);
+        variants.push(encode(&resized, format)?);
+        if webp {
+            variants.push(encode_webp(&resized));
+        }
+    }
+    if webp {
+        variants.push(encode_webp(&img));
+    }
+
+    Ok(Processed { original, variants })
+}
+
+fn mime(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::PNG => "image/png",
+        _ => "image/jpeg",
+    }
+}
+
+fn encode(img: &DynamicImage, format: ImageFormat) -> Result<Encoded, String> {
+    let mut data = vec![];
+    let output = match format {
+        ImageFormat::PNG => ImageOutputFormat::PNG,
+        _ => ImageOutputFormat::JPEG(JPEG_QUALITY),
+    };
+    img.write_to(&mut data, output).map_err(|e| e.to_string())?;
+    Ok(Encoded { data, content_type: mime(format), width: img.width(), height: img.height() })
+}
+
+fn encode_webp(img: &DynamicImage) -> Encoded {
+    let rgba = img.to_rgba();
+    let data = webp::Encoder::from_rgba(&rgba, img.width(), img.height())
+        .encode(WEBP_QUALITY)
+        .to_vec();
+    Encoded { data, content_type: "image/webp", width: img.width(), height: img.height() }
+}
+
+/// Apply an EXIF orientation, since it's lost along with the rest of the
+/// metadata.
+fn orient(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Drop metadata segments from a JPEG, returning the EXIF orientation found
+/// along the way.
+fn strip_jpeg(data: &[u8]) -> Result<(Vec<u8>, u16), String> {
+    let invalid = || "Invalid JPEG".to_string();
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(invalid());
+    }
+
+    let mut out = data[..2].to_vec();
+    let mut orientation = 1;
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return Err(invalid());
+        }
+        let marker = data[pos + 1];
+        // Start of scan: the rest is image data.
+        if marker == 0xDA {
+            break;
+        }
+        let len = usize::from(data[pos + 2]) << 8 | usize::from(data[pos + 3]);
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return Err(invalid());
+        }
+        if marker == 0xE1 {
+            if let Some(o) = exif_orientation(&data[pos + 4..end]) {
+                orientation = o;
+            }
+        }
+        if !JPEG_METADATA.contains(&marker) {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+    out.extend_from_slice(&data[pos..]);
+    Ok((out, orientation))
+}
+
+/// Read the orientation tag from IFD0 of an APP1 EXIF payload.
+fn exif_orientation(app1: &[u8]) -> Option<u16> {
+    let tiff = app1.get(6..).filter(|_| app1.starts_with(b"Exif\0\0"))?;
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |i: usize| -> Option<u16> {
+        let b = tiff.get(i..i + 2)?;
+        Some(if big_endian { u16::from(b[0]) << 8 | u16::from(b[1]) } else { u16::from(b[1]) << 8 | u16::from(b[0]) })
+    };
+    let u32_at = |i: usize| -> Option<u32> {
+        let (hi, lo) = if big_endian { (u16_at(i)?, u16_at(i + 2)?) } else { (u16_at(i + 2)?, u16_at(i)?) };
+        Some(u32::from(hi) << 16 | u32::from(lo))
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|o| (1..=8).contains(o))
+}
+
+/// Drop textual and EXIF chunks from a PNG.
+fn strip_png(data: &[u8]) -> Result<Vec<u8>, String> {
+    let invalid = || "Invalid PNG".to_string();
+    if !data.starts_with(PNG_SIGNATURE) {
+        return Err(invalid());
+    }
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let header = data.get(pos..pos + 8).ok_or_else(invalid)?;
+        let len = (u32::from(header[0]) << 24 | u32::from(header[1]) << 16
+            | u32::from(header[2]) << 8 | u32::from(header[3])) as usize;
+        // Length, type, data and CRC.
+        let end = pos + 12 + len;
+        if end > data.len() {
+            return Err(invalid());
+        }
+        if !PNG_METADATA.iter().any(|t| header[4..8] == t[..]) {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_type_goes_by_contents() {
+        assert_eq!(supported_type("application/octet-stream", &[0xFF, 0xD8, 0xFF, 0xE1]), Some("image/jpeg"));
+        assert_eq!(supported_type("image/jpg", &[0xFF, 0xD8, 0xFF, 0xE0]), Some("image/jpeg"));
+        assert_eq!(supported_type("text/plain", b"\x89PNG\r\n\x1a\n\0\0"), Some("image/png"));
+        assert_eq!(supported_type("Image/JPEG; charset=binary", b"not a jpeg"), Some("image/jpeg"));
+        assert_eq!(supported_type("image/gif", b"GIF89a"), None);
+    }
+}
@@ -1,4 +1,15 @@
 #![feature(async_await)]
 #![feature(duration_float)]
+
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate prometheus;
+
+#[allow(dead_code)]
+mod cache;
 mod db;
-pub use db::{NewPost, Post};
+mod error;
+pub use db::{
+    Asset, BatchItemResult, BatchMode, BatchOperation, BatchRequest, BatchResponse,
+    ExportedPost, NewPost, Post, PostStatus, PostSummary,
+};
+pub use error::{ApiError, ErrorCode};
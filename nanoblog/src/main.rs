@@ -3,45 +3,285 @@
 
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate prometheus;
-#[macro_use] extern crate tera;
 
+use std::path::PathBuf;
 use std::thread;
 
+mod admin;
 mod api;
+mod assets;
+mod batch;
+mod cache;
+mod changes;
+mod config;
 mod db;
+#[allow(dead_code)]
+mod error;
+mod highlight;
+mod http_cache;
+mod images;
+mod markdown;
 mod middleware;
+mod openapi;
 mod posts;
+mod sanitize;
+mod static_files;
+mod templates;
+mod webhooks;
 
+/// Route templates used to label request metrics, besides the API operations.
+/// Keep in sync with the routes registered in `serve`.
+const ROUTES: &[&str] = &[
+    openapi::DOCUMENT_PATH,
+    "/_health",
+    "/static/*path",
+    "/assets/:hash/:name",
+    "/",
+    "/:post",
+];
 
-fn main() -> Result<(), std::io::Error> {
-    let db = db::Database::new();
-    let metrics = middleware::PromMetrics::default();
-    let bearer_protection = middleware::BearerAuth::default();
+#[derive(structopt::StructOpt, Debug)]
+#[structopt(name = "nanoblog")]
+/// Serve the blog
+struct Args {
+    /// TOML config file. Defaults to $NANOBLOG_CONFIG, then /etc/nanoblog/config.toml
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Address to serve the blog on
+    #[structopt(long = "listen")]
+    listen: Option<String>,
+
+    /// Address to serve metrics on, or "off"
+    #[structopt(long = "metrics-listen")]
+    metrics_listen: Option<String>,
+
+    /// Theme to render pages with
+    #[structopt(long = "theme")]
+    theme: Option<String>,
+
+    /// Use Tera templates from this directory instead of the theme's
+    #[structopt(long = "template-dir", parse(from_os_str))]
+    template_dir: Option<PathBuf>,
+
+    /// Reload the theme when it changes on disk
+    #[structopt(long = "watch-templates")]
+    watch_templates: bool,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(structopt::StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "serve")]
+    /// Run the blog server (the default)
+    Serve,
+    #[structopt(name = "check-config")]
+    /// Validate the configuration and print it
+    CheckConfig,
+    #[structopt(name = "migrate")]
+    /// Backfill storage bookkeeping for posts saved by older versions
+    Migrate,
+    #[structopt(name = "token")]
+    /// Manage API bearer tokens
+    Token(TokenCommand),
+    #[structopt(name = "export")]
+    /// Export every post as JSON
+    Export {
+        /// Write to a file instead of stdout
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    #[structopt(name = "import")]
+    /// Import posts from an export file
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    #[structopt(name = "render")]
+    /// Preview a markdown file through the production rendering pipeline
+    Render {
+        /// Title to render the page with. Defaults to the file name
+        #[structopt(long = "title")]
+        title: Option<String>,
+        /// Only print the post body, without the page template
+        #[structopt(long = "body-only")]
+        body_only: bool,
+        /// Skip HTML sanitization
+        #[structopt(long = "trusted-html")]
+        trusted_html: bool,
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+#[derive(structopt::StructOpt, Debug)]
+enum TokenCommand {
+    #[structopt(name = "create")]
+    /// Create a new token and print it
+    Create {
+        /// Allow the token to publish trusted HTML
+        #[structopt(long = "admin")]
+        admin: bool,
+    },
+    #[structopt(name = "revoke")]
+    /// Revoke an existing token
+    Revoke {
+        token: String,
+    },
+}
+
+/// Build the config from the file, then the environment, then flags, each
+/// taking precedence over the last.
+fn load_config(args: &Args) -> Result<config::Config, config::ConfigError> {
+    let mut config = config::Config::from_file(args.config.as_ref().map(PathBuf::as_path))?;
+    config.apply_env()?;
+
+    if let Some(listen) = &args.listen {
+        config.server.listen = listen.clone();
+    }
+    if let Some(listen) = &args.metrics_listen {
+        config.metrics.listen = if listen == "off" { None } else { Some(listen.clone()) };
+    }
+    if let Some(theme) = &args.theme {
+        config.render.theme = theme.clone();
+    }
+    if let Some(dir) = &args.template_dir {
+        config.render.template_dir = Some(dir.clone());
+    }
+    if args.watch_templates {
+        config.render.watch_templates = true;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+#[paw::main]
+fn main(args: Args) -> Result<(), std::io::Error> {
+    // Access logs go to stdout at `info`; `RUST_LOG` overrides the filter.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn,nanoblog=info"))
+        .target(env_logger::Target::Stdout)
+        .init();
+
+    let config = match load_config(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("nanoblog: {}", e);
+            std::process::exit(2);
+        },
+    };
+    config::set(config.clone());
+    cache::set_capacity(config.cache.page_cache_size);
+    db::set_change_log_size(config.changes.log_size);
+
+    let db = db::Database::new(
+        config.storage.redis_host.as_ref().map(String::as_str),
+        config.storage.redis_password.as_ref().map(String::as_str),
+    );
+
+    match args.cmd.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config, db),
+        Command::CheckConfig => admin::check_config(&config),
+        Command::Migrate => admin::migrate(&db),
+        Command::Token(TokenCommand::Create {admin}) => admin::create_token(&db, admin),
+        Command::Token(TokenCommand::Revoke {token}) => admin::revoke_token(&db, token),
+        Command::Export {output} => admin::export(&db, output.as_ref().map(PathBuf::as_path)),
+        Command::Import {file} => admin::import(&db, &file),
+        Command::Render {title, body_only, trusted_html, file} => {
+            admin::render(&db, &file, title, body_only, trusted_html)
+        },
+    }
+}
+
+fn serve(config: config::Config, db: db::Database) -> Result<(), std::io::Error> {
+    templates::load(&config.render).map_err(|e| std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Can't load theme {:?}: {}", config.render.theme, e),
+    ))?;
+    if config.render.watch_templates {
+        templates::watch(config.render.clone())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    }
+
+    let operations = api::operations();
+    let mut routes: Vec<String> = ROUTES.iter().map(|r| r.to_string()).collect();
+    for root in &[openapi::API_ROOT, openapi::LEGACY_API_ROOT] {
+        routes.extend(operations.iter().map(|op| format!("{}{}", root, op.path)));
+    }
+    let metrics = middleware::PromMetrics::new(&routes);
+    let rate_limit = middleware::RateLimit::new(&config.rate_limit, &config.proxy);
+    let bearer_protection = middleware::BearerAuth::new(db.clone());
+    let compression = middleware::Compression::new(config.server.compression_min_size);
+    let metrics_db = db.clone();
+    webhooks::start(db.clone());
+    admin::start_migration(db.clone());
     let mut app = tide::App::new(db);
 
-    // Expose the metrics infornation on a different port (hopefully internal!).
-    thread::spawn(move || {
-        let mut app = tide::App::new(());
-        app.at("/metrics").get(middleware::report);
-        let _ = app.serve("0.0.0.0:8000");
-    });
+    // Expose the metrics information on a different port, unless disabled.
+    if let Some(metrics_addr) = config.metrics.listen.clone() {
+        // A failed bind inside the listener thread would only take down that
+        // thread, so check the address is usable before starting it.
+        std::net::TcpListener::bind(&*metrics_addr)
+            .map_err(|e| std::io::Error::new(
+                e.kind(),
+                format!("Can't bind metrics listener to {}: {}", metrics_addr, e),
+            ))?;
 
-    app.middleware(tide::middleware::RootLogger::new());
+        let metrics_auth = middleware::MetricsAuth::new(&config.auth);
+        thread::spawn(move || {
+            let mut app = tide::App::new(metrics_db);
+            app.middleware(metrics_auth);
+            app.at("/metrics").get(middleware::report);
+            if let Err(e) = app.serve(&*metrics_addr) {
+                eprintln!("Metrics listener failed on {}: {}", metrics_addr, e);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    // First, so everything after it sees the client rather than the proxy.
+    app.middleware(middleware::TrustedProxies::new(&config.proxy));
+    app.middleware(middleware::AccessLog);
+    app.middleware(compression);
     app.middleware(metrics);
+    app.middleware(rate_limit);
     app.middleware(bearer_protection);
+    app.middleware(middleware::ApiDeprecation);
 
-    app.at("/api").nest(|router| {
-        router.at("/ping").get(async move |_| "OK\n");
-        router.at("/posts").get(api::list_posts);
-        router.at("/posts").post(api::upsert_post);
-        router.at("/posts/:post").get(api::get_raw_post);
-    });
+    api_routes(&mut app, &operations);
 
     app.at("/_health")
         .get(async move |_| format!("{}\n", env!("CARGO_PKG_VERSION")));
 
+    app.at("/static/*path").get(static_files::serve);
+    app.at("/assets/:hash/:name").get(assets::serve);
     app.at("/").get(posts::list_posts);
     app.at("/:post").get(posts::get_post);
 
-    app.serve("0.0.0.0:80")
+    app.serve(&*config.server.listen)
+}
+
+/// Route the API under each of its roots, with its description under the
+/// current one.
+fn api_routes(app: &mut tide::App<db::Database>, operations: &[openapi::Operation]) {
+    app.at(openapi::API_ROOT).nest(|router| {
+        router.at("/openapi.json").get(openapi::serve);
+        register(router, operations);
+    });
+    app.at(openapi::LEGACY_API_ROOT).nest(|router| register(router, operations));
+}
+
+/// Route each API operation under the router's root.
+fn register(router: &mut tide::Router<db::Database>, operations: &[openapi::Operation]) {
+    for op in operations {
+        let route = router.at(op.path);
+        match op.method {
+            openapi::Method::Get => route.get(op.handler),
+            openapi::Method::Post => route.post(op.handler),
+            openapi::Method::Patch => route.patch(op.handler),
+            openapi::Method::Delete => route.delete(op.handler),
+        };
+    }
 }
@@ -0,0 +1,400 @@
+use std::collections::{HashMap, HashSet};
+
+use pulldown_cmark::{Event, Parser, Options, Tag, html};
+use serde::{Serialize, Deserialize};
+use slug::slugify;
+
+use crate::config;
+use crate::db::Asset;
+use crate::highlight;
+
+const FRONT_MATTER_DELIM: &str = "+++";
+
+lazy_static! {
+    /// Site-wide defaults from the `render.markdown` config section.
+    pub static ref SITE_OPTIONS: RenderOptions = config::get().render.markdown.clone();
+}
+
+/// Markdown extensions that can be toggled for the whole site, and then
+/// overridden by a post's front matter.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderOptions {
+    pub footnotes: bool,
+    pub tasklists: bool,
+    pub smart_punctuation: bool,
+    pub heading_anchors: bool,
+    pub toc: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            footnotes: true,
+            tasklists: true,
+            smart_punctuation: true,
+            heading_anchors: true,
+            toc: true,
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Apply any overrides set in a post's front matter.
+    pub fn merge(&self, fm: &FrontMatter) -> Self {
+        Self {
+            footnotes: fm.footnotes.unwrap_or(self.footnotes),
+            tasklists: fm.tasklists.unwrap_or(self.tasklists),
+            smart_punctuation: fm.smart_punctuation.unwrap_or(self.smart_punctuation),
+            heading_anchors: fm.heading_anchors.unwrap_or(self.heading_anchors),
+            toc: fm.toc.unwrap_or(self.toc),
+        }
+    }
+}
+
+/// Optional TOML block at the very top of a post, fenced by `+++` lines.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FrontMatter {
+    pub footnotes: Option<bool>,
+    pub tasklists: Option<bool>,
+    pub smart_punctuation: Option<bool>,
+    pub heading_anchors: Option<bool>,
+    pub toc: Option<bool>,
+}
+
+impl FrontMatter {
+    /// Split the front matter off of a post body. Bodies without a (valid)
+    /// front matter block are returned untouched.
+    pub fn split(contents: &str) -> (Self, &str) {
+        let start = format!("{}\n", FRONT_MATTER_DELIM);
+        let end = format!("\n{}\n", FRONT_MATTER_DELIM);
+        if !contents.starts_with(&start) {
+            return (Self::default(), contents);
+        }
+
+        let rest = &contents[start.len()..];
+        match rest.find(&end) {
+            Some(idx) => match toml::from_str(&rest[..idx]) {
+                Ok(fm) => (fm, &rest[idx + end.len()..]),
+                Err(_) => (Self::default(), contents),
+            },
+            None => (Self::default(), contents),
+        }
+    }
+}
+
+/// Table of contents entry for a single heading.
+#[derive(Serialize, Debug, Clone)]
+pub struct TocEntry {
+    pub level: i32,
+    pub id: String,
+    pub title: String,
+}
+
+pub struct Rendered {
+    pub html: String,
+    pub toc: Vec<TocEntry>,
+}
+
+/// Render a post body, honouring its front matter over the site defaults.
+/// `assets` holds the metadata of hosted images the body refers to, keyed by
+/// hash, see `asset_hashes`.
+pub fn render(contents: &str, assets: &HashMap<String, Asset>) -> Rendered {
+    let (fm, body) = FrontMatter::split(contents);
+    render_with(body, &SITE_OPTIONS.merge(&fm), assets)
+}
+
+/// The hash of a hosted asset URL, `/assets/<hash>/<name>`.
+pub fn asset_hash(url: &str) -> Option<&str> {
+    let mut parts = url.trim_start_matches("/assets/").splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(hash), Some(_)) if url.starts_with("/assets/") && hash.len() == 64 => Some(hash),
+        _ => None,
+    }
+}
+
+/// Hashes of the hosted assets embedded as images in a post, whose metadata
+/// is needed to render it.
+pub fn asset_hashes(contents: &str) -> Vec<String> {
+    let mut hashes: Vec<String> = Parser::new(contents)
+        .filter_map(|event| match event {
+            Event::Start(Tag::Image(url, _)) => asset_hash(&url).map(String::from),
+            _ => None,
+        })
+        .collect();
+    hashes.sort();
+    hashes.dedup();
+    hashes
+}
+
+/// Render markdown contents with an explicit set of options.
+pub fn render_with(contents: &str, opts: &RenderOptions, assets: &HashMap<String, Asset>) -> Rendered {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    if opts.footnotes {
+        options.insert(Options::ENABLE_FOOTNOTES);
+    }
+    if opts.tasklists {
+        options.insert(Options::ENABLE_TASKLISTS);
+    }
+    let parser = Parser::new_ext(contents, options);
+
+    let mut events: Vec<Event> = vec![];
+    let mut toc = vec![];
+    let mut ids: HashSet<String> = HashSet::new();
+    let mut quotes = SmartQuotes::default();
+
+    // Fenced code is buffered until the end of the block so it can be handed to
+    // the highlighter in one piece. Headings are buffered the same way so their
+    // text can be turned into an anchor.
+    let mut code: Option<(String, String)> = None;
+    let mut heading: Option<(i32, Vec<Event>)> = None;
+    // Hosted images are buffered too, to use their alt text in a responsive
+    // `<img>` tag.
+    let mut image: Option<(&Asset, String, String, Vec<Event>)> = None;
+
+    for event in parser {
+        quotes.track(&event);
+        let event = match event {
+            Event::Start(Tag::CodeBlock(info)) => {
+                code = Some((info.to_string(), String::new()));
+                continue;
+            },
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some((info, buf)) = code.take() {
+                    events.push(Event::Html(highlight::highlight(&info, &buf).into()));
+                }
+                continue;
+            },
+            Event::Text(text) => {
+                if let Some((_, ref mut buf)) = code {
+                    buf.push_str(&text);
+                    continue;
+                }
+                if opts.smart_punctuation {
+                    Event::Text(quotes.apply(&text).into())
+                } else {
+                    Event::Text(text)
+                }
+            },
+            Event::Start(Tag::Image(url, title)) => {
+                let asset = asset_hash(&url)
+                    .and_then(|hash| assets.get(hash))
+                    .filter(|a| a.width.is_some() && a.height.is_some());
+                match asset {
+                    Some(asset) => {
+                        image = Some((asset, url.to_string(), title.to_string(), vec![]));
+                        continue;
+                    },
+                    None => Event::Start(Tag::Image(url, title)),
+                }
+            },
+            Event::End(Tag::Image(_, _)) if image.is_some() => {
+                let (asset, url, title, inner) = image.take().unwrap();
+                let html = responsive_image(asset, &url, &title, &plain_text(&inner));
+                match heading {
+                    Some((_, ref mut inner)) => inner.push(Event::Html(html.into())),
+                    None => events.push(Event::Html(html.into())),
+                }
+                continue;
+            },
+            Event::Start(Tag::Header(level)) if opts.heading_anchors || opts.toc => {
+                heading = Some((level, vec![]));
+                continue;
+            },
+            Event::End(Tag::Header(_)) if heading.is_some() => {
+                let (level, inner) = heading.take().unwrap();
+                let title = plain_text(&inner);
+                let id = unique_id(&mut ids, &title);
+
+                let mut inner_html = String::new();
+                html::push_html(&mut inner_html, inner.into_iter());
+
+                let anchor = if opts.heading_anchors {
+                    format!(" <a class=\"anchor\" href=\"#{}\" aria-hidden=\"true\">#</a>", id)
+                } else {
+                    String::new()
+                };
+                events.push(Event::Html(
+                    format!("<h{0} id=\"{1}\">{2}{3}</h{0}>\n", level, id, inner_html, anchor).into()
+                ));
+                toc.push(TocEntry { level, id, title });
+                continue;
+            },
+            e => e,
+        };
+
+        match (&mut image, &mut heading) {
+            (Some((_, _, _, inner)), _) => inner.push(event),
+            (None, Some((_, inner))) => inner.push(event),
+            (None, None) => events.push(event),
+        }
+    }
+
+    let mut html_output = String::new();
+    html::push_html(&mut html_output, events.into_iter());
+
+    if !opts.toc {
+        toc.clear();
+    }
+    Rendered { html: html_output, toc }
+}
+
+/// An `<img>` sized by the asset's dimensions, offering its resized
+/// variants, wrapped in a `<picture>` when there are WebP ones.
+fn responsive_image(asset: &Asset, src: &str, title: &str, alt: &str) -> String {
+    let width = asset.width.unwrap_or_default();
+    let height = asset.height.unwrap_or_default();
+    let srcset = |content_type: &str| -> Vec<String> {
+        asset.variants.iter()
+            .filter(|v| v.content_type == content_type)
+            .map(|v| format!("{} {}w", v.url(), v.width))
+            .collect()
+    };
+
+    let mut same_type = srcset(&asset.content_type);
+    same_type.push(format!("{} {}w", src, width));
+    let title = if title.is_empty() {
+        String::new()
+    } else {
+        format!(" title=\"{}\"", escape_html(title))
+    };
+    let img = format!(
+        "<img src=\"{}\" srcset=\"{}\" width=\"{}\" height=\"{}\" alt=\"{}\"{} loading=\"lazy\">",
+        escape_html(src),
+        escape_html(&same_type.join(", ")),
+        width,
+        height,
+        escape_html(alt),
+        title,
+    );
+
+    let webp = srcset("image/webp");
+    if webp.is_empty() {
+        img
+    } else {
+        format!(
+            "<picture><source type=\"image/webp\" srcset=\"{}\">{}</picture>",
+            escape_html(&webp.join(", ")),
+            img,
+        )
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn plain_text(events: &[Event]) -> String {
+    events.iter()
+        .filter_map(|e| match e {
+            Event::Text(t) | Event::Code(t) => Some(t.as_ref()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Derive a stable `id` from heading text, suffixing repeats so they stay
+/// unique, even against a heading whose own text ends in a number.
+fn unique_id(ids: &mut HashSet<String>, title: &str) -> String {
+    let base = match slugify(title) {
+        ref s if s.is_empty() => "section".to_string(),
+        s => s,
+    };
+    let mut id = base.clone();
+    let mut n = 0;
+    while ids.contains(&id) {
+        n += 1;
+        id = format!("{}-{}", base, n);
+    }
+    ids.insert(id.clone());
+    id
+}
+
+/// Typographic replacements for dashes, ellipses and quotes. The previous
+/// character is carried across text events so quotes split by inline markup
+/// still face the right way.
+#[derive(Default)]
+struct SmartQuotes {
+    prev: Option<char>,
+}
+
+impl SmartQuotes {
+    /// Account for an event that isn't text: a new block starts afresh, and
+    /// a line break counts as whitespace.
+    fn track(&mut self, event: &Event) {
+        match event {
+            Event::Start(Tag::Paragraph)
+            | Event::Start(Tag::Header(_))
+            | Event::Start(Tag::Item)
+            | Event::Start(Tag::BlockQuote)
+            | Event::Start(Tag::TableCell) => self.prev = None,
+            Event::SoftBreak | Event::HardBreak => self.prev = Some(' '),
+            _ => {},
+        }
+    }
+
+    fn apply(&mut self, text: &str) -> String {
+        let text = text
+            .replace("---", "\u{2014}")
+            .replace("--", "\u{2013}")
+            .replace("...", "\u{2026}");
+
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            let opening = match self.prev {
+                None => true,
+                Some(p) => p.is_whitespace() || "([{\u{2014}\u{2013}".contains(p),
+            };
+            out.push(match (c, opening) {
+                ('"', true) => '\u{201c}',
+                ('"', false) => '\u{201d}',
+                ('\'', true) => '\u{2018}',
+                ('\'', false) => '\u{2019}',
+                (c, _) => c,
+            });
+            self.prev = Some(c);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(contents: &str) -> String {
+        render_with(contents, &RenderOptions::default(), &HashMap::new()).html
+    }
+
+    #[test]
+    fn heading_ids_stay_unique() {
+        let ids: Vec<String> = render_with("# Foo\n# Foo\n# Foo 1", &RenderOptions::default(), &HashMap::new())
+            .toc
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, vec!["foo", "foo-1", "foo-1-1"]);
+    }
+
+    #[test]
+    fn quotes_open_each_paragraph() {
+        assert_eq!(
+            html("\"One\"\n\n\"Two\""),
+            "<p>\u{201c}One\u{201d}</p>\n<p>\u{201c}Two\u{201d}</p>\n",
+        );
+    }
+
+    #[test]
+    fn quotes_open_after_a_line_break() {
+        assert_eq!(
+            html("\"One\"\n\"Two\""),
+            "<p>\u{201c}One\u{201d}\n\u{201c}Two\u{201d}</p>\n",
+        );
+    }
+}
@@ -1,4 +1,14 @@
-use prometheus::{Encoder, HistogramVec, IntCounterVec, TextEncoder};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use flate2::Compression as Level;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use ipnet::IpNet;
+use lru::LruCache;
+use prometheus::{Encoder, HistogramVec, IntCounterVec, IntGauge, TextEncoder};
+use sha2::{Digest, Sha256};
 
 use futures::future::BoxFuture;
 use futures::prelude::*;
@@ -9,22 +19,47 @@ use tide::{
     Context, Response,
 };
 
-use crate::db::Database;
+use crate::config::{self, AuthConfig, ForwardedHeader, Limit, ProxyConfig, RateLimitConfig};
+use crate::db::{Database, TokenRole};
+use crate::error::{ApiError, ErrorCode};
+use crate::http_cache;
+use crate::openapi;
 
 lazy_static! {
     static ref LATENCY: HistogramVec = register_histogram_vec!(
         "http_request_duration_seconds",
         "The HTTP request latencies in seconds.",
-        &["handler"]
+        &["handler", "method"]
     )
     .unwrap();
     static ref RES_STATUS: IntCounterVec = register_int_counter_vec!(
         "http_res_status_by_handler",
-        "Handler HTTP status codes",
-        &["handler", "status_code"]
+        "Handler HTTP status classes",
+        &["handler", "method", "status_class"]
+    )
+    .unwrap();
+    static ref POST_VIEWS: IntCounterVec = register_int_counter_vec!(
+        "post_views_total",
+        "Successful views of published posts, by slug",
+        &["slug"]
+    )
+    .unwrap();
+
+    static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "rate_limited_requests_total",
+        "Requests refused with a 429, by rate limit.",
+        &["limit"]
+    )
+    .unwrap();
+    static ref RATE_LIMIT_CLIENTS: IntGauge = register_int_gauge!(
+        "rate_limit_clients",
+        "Clients currently tracked by the rate limiter."
     )
     .unwrap();
 
+    /// Per-slug view counting is opt-in through `metrics.post_views`.
+    pub static ref POST_VIEW_METRICS: bool = config::get().metrics.post_views;
+
     static ref UNAUTHORIZED: http::Response<String> = http::Response::builder()
         .status(http::StatusCode::UNAUTHORIZED)
         .header(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")
@@ -32,11 +67,269 @@ lazy_static! {
         .unwrap();
 }
 
-pub struct PromMetrics;
+/// Count a view of a post. Callers must check that the slug is published, so
+/// the number of series stays bounded by the number of posts.
+pub fn record_post_view(slug: &str) {
+    POST_VIEWS.with_label_values(&[slug]).inc();
+}
+
+/// Who made a request, as resolved by `TrustedProxies`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    /// The client's address, if it's known.
+    pub ip: Option<IpAddr>,
+    /// `http` or `https`, as the client connected.
+    pub scheme: &'static str,
+    /// The host the client asked for.
+    pub host: Option<String>,
+}
+
+impl ClientInfo {
+    /// The info `TrustedProxies` stored for a request.
+    pub fn of<T>(cx: &Context<T>) -> Self {
+        cx.extensions().get::<ClientInfo>().cloned().unwrap_or(ClientInfo {
+            ip: None,
+            scheme: "http",
+            host: None,
+        })
+    }
+
+    /// The address for logs.
+    pub fn ip_label(&self) -> String {
+        self.ip.map_or_else(|| "unknown".into(), |ip| ip.to_string())
+    }
+
+    /// Absolute URL of a path on this site, as the client reached it.
+    pub fn url(&self, path: &str) -> Option<String> {
+        self.host.as_ref().map(|host| format!("{}://{}{}", self.scheme, host, path))
+    }
+
+    /// Drop a host that isn't safe to build URLs from.
+    fn checked(mut self) -> Self {
+        if !self.host.as_ref().map_or(false, |h| valid_host(h)) {
+            self.host = None;
+        }
+        self
+    }
+}
+
+/// One proxy hop, as recorded in `Forwarded` or the `X-Forwarded-*` headers.
+#[derive(Debug, Default)]
+struct Hop {
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    }
+}
+
+/// The address in a forwarded node, e.g. `192.0.2.1`, `192.0.2.1:4711` or
+/// `[2001:db8::1]:4711`. Obfuscated and `unknown` nodes have none.
+fn node_ip(node: &str) -> Option<IpAddr> {
+    let node = unquote(node);
+    if node.starts_with('[') {
+        return node[1..].split(']').next()?.parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        let mut parts = node.rsplitn(2, ':');
+        let (_port, addr) = (parts.next()?, parts.next()?);
+        addr.parse().ok()
+    })
+}
+
+fn header_values<'h>(headers: &'h http::HeaderMap, name: &str) -> Vec<&'h str> {
+    headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Every hop the proxies recorded in `header`, the client's first. The
+/// `X-Forwarded-*` headers' scheme and host apply to the last hop.
+fn hops(headers: &http::HeaderMap, header: ForwardedHeader) -> Vec<Hop> {
+    if header == ForwardedHeader::Forwarded {
+        return header_values(headers, "forwarded").into_iter()
+            .map(|element| {
+                let mut hop = Hop::default();
+                for pair in element.split(';') {
+                    let mut kv = pair.splitn(2, '=');
+                    let (key, value) = match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) => (k.trim().to_lowercase(), unquote(v)),
+                        _ => continue,
+                    };
+                    match key.as_str() {
+                        "for" => hop.ip = node_ip(value),
+                        "proto" => hop.proto = Some(value.to_lowercase()),
+                        "host" => hop.host = Some(value.to_string()),
+                        _ => {},
+                    }
+                }
+                hop
+            })
+            .collect();
+    }
+
+    let mut hops: Vec<Hop> = header_values(headers, "x-forwarded-for").into_iter()
+        .map(|node| Hop { ip: node_ip(node), ..Hop::default() })
+        .collect();
+    if let Some(last) = hops.last_mut() {
+        last.proto = header_values(headers, "x-forwarded-proto").last().map(|p| p.to_lowercase());
+        last.host = header_values(headers, "x-forwarded-host").last().map(|h| h.to_string());
+    }
+    hops
+}
+
+/// Whether a host is safe to build URLs from.
+fn valid_host(host: &str) -> bool {
+    !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c))
+}
+
+/// Resolves the real client address and scheme from the headers set by
+/// trusted proxies, storing a `ClientInfo` for the middleware and endpoints
+/// after it. The address is the nearest hop outside the trusted networks.
+///
+/// tide doesn't expose the connection's own peer address, so with proxies
+/// configured the server must only be reachable through them.
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    pub fn new(config: &ProxyConfig) -> Self {
+        Self { networks: config.networks(), header: config.header }
+    }
+
+    fn trusted(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(ip))
+    }
+
+    fn resolve(&self, headers: &http::HeaderMap) -> ClientInfo {
+        let host_header = headers.get(http::header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let mut info = ClientInfo { ip: None, scheme: "http", host: host_header };
+        if self.networks.is_empty() {
+            return info.checked();
+        }
 
-impl Default for PromMetrics {
-    fn default() -> Self {
-        PromMetrics {}
+        let hops = hops(headers, self.header);
+        let mut scheme = None;
+        let mut host = None;
+        let mut ip = None;
+        // Walk back from the nearest hop. Anything added before the first
+        // untrusted address could have been made up by the client.
+        for hop in hops.iter().rev() {
+            scheme = hop.proto.clone().or(scheme);
+            host = hop.host.clone().or(host);
+            ip = hop.ip;
+            match hop.ip {
+                Some(ref addr) if self.trusted(addr) => continue,
+                _ => break,
+            }
+        }
+        info.ip = ip;
+        if let Some(h) = host {
+            info.host = Some(h);
+        }
+        if scheme.as_ref().map(String::as_str) == Some("https") {
+            info.scheme = "https";
+        }
+        info.checked()
+    }
+}
+
+impl<T: Send + Sync + 'static> Middleware<T> for TrustedProxies {
+    fn handle<'a>(&'a self, mut cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
+        FutureExt::boxed(async move {
+            let info = self.resolve(cx.headers());
+            cx.extensions_mut().insert(info);
+            next.run(cx).await
+        })
+    }
+}
+
+/// Logs each request with the client's resolved address.
+pub struct AccessLog;
+
+impl<T: Send + Sync + 'static> Middleware<T> for AccessLog {
+    fn handle<'a>(&'a self, cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
+        FutureExt::boxed(async move {
+            let start = std::time::Instant::now();
+            let client = ClientInfo::of(&cx).ip_label();
+            let method = cx.method().clone();
+            let path = cx.uri().path().to_string();
+
+            let res = next.run(cx).await;
+            log::info!(
+                "{} {} {} {} {}ms",
+                client,
+                method,
+                path,
+                res.status().as_u16(),
+                start.elapsed().as_millis(),
+            );
+            res
+        })
+    }
+}
+
+/// Labels requests by the route template they matched rather than the raw
+/// path, so that every slug doesn't become its own time series.
+pub struct PromMetrics {
+    routes: Vec<Vec<String>>,
+}
+
+impl PromMetrics {
+    pub fn new<S: AsRef<str>>(routes: &[S]) -> Self {
+        PromMetrics {
+            routes: routes.iter()
+                .map(|r| r.as_ref().split('/').filter(|s| !s.is_empty()).map(String::from).collect())
+                .collect(),
+        }
+    }
+
+    /// Find the registered template matching a path, preferring static
+    /// segments over parameters the same way the router does. A trailing
+    /// `*name` segment matches the rest of the path.
+    fn route_for(&self, path: &str) -> String {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        self.routes.iter()
+            .filter(|route| {
+                let wildcard = route.last().map_or(false, |r| r.starts_with('*'));
+                let len_ok = if wildcard {
+                    segments.len() >= route.len()
+                } else {
+                    route.len() == segments.len()
+                };
+                len_ok && route.iter().zip(&segments)
+                    .all(|(r, s)| r.starts_with(':') || r.starts_with('*') || r == s)
+            })
+            .max_by_key(|route| route.iter().filter(|r| !r.starts_with(':') && !r.starts_with('*')).count())
+            .map(|route| format!("/{}", route.join("/")))
+            .unwrap_or_else(|| "unmatched".into())
+    }
+}
+
+fn method_label(method: &http::Method) -> &'static str {
+    match *method {
+        http::Method::GET => "GET",
+        http::Method::HEAD => "HEAD",
+        http::Method::POST => "POST",
+        http::Method::PUT => "PUT",
+        http::Method::PATCH => "PATCH",
+        http::Method::DELETE => "DELETE",
+        http::Method::OPTIONS => "OPTIONS",
+        _ => "other",
     }
 }
 
@@ -44,26 +337,28 @@ impl<T: Send + Sync + 'static> Middleware<T> for PromMetrics {
     fn handle<'a>(&'a self, cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
         FutureExt::boxed(async move {
             let t = std::time::Instant::now();
-            let path = cx.uri().path().to_owned();
+            let route = self.route_for(cx.uri().path());
+            let method = method_label(cx.method());
 
             let res = next.run(cx).await;
 
-            let status = res.status();
-            // Only store the info if it was a recognized route to prevent metrics DoS
-            if status != 404 {
-                RES_STATUS
-                    .with_label_values(&[&path, &status.as_u16().to_string()])
-                    .inc();
-                LATENCY
-                    .with_label_values(&[&path])
-                    .observe(t.elapsed().as_secs_f64());
-            }
+            let status_class = format!("{}xx", res.status().as_u16() / 100);
+            RES_STATUS
+                .with_label_values(&[&route, method, &status_class])
+                .inc();
+            LATENCY
+                .with_label_values(&[&route, method])
+                .observe(t.elapsed().as_secs_f64());
             res
         })
     }
 }
 
-pub async fn report<T>(_: Context<T>) -> EndpointResult {
+pub async fn report(cx: Context<Database>) -> EndpointResult {
+    let db = cx.app_data();
+    db.record_pool_metrics();
+    db.record_post_counts().await;
+
     let mut buf = vec![];
     let encoder = TextEncoder::new();
     let metrics_families = prometheus::gather();
@@ -92,36 +387,120 @@ pub async fn report<T>(_: Context<T>) -> EndpointResult {
 }
 
 
+/// Credentials required to scrape the metrics listener.
+#[derive(Debug, Clone)]
+pub enum MetricsCredentials {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+/// Protects the metrics listener with either basic auth or a static bearer
+/// token. With no credentials configured every request is let through.
+pub struct MetricsAuth {
+    pub credentials: Option<MetricsCredentials>,
+}
+
+impl MetricsAuth {
+    pub fn new(auth: &AuthConfig) -> Self {
+        let user = auth.metrics_username.clone();
+        let pw = auth.metrics_password.clone();
+        let token = auth.metrics_token.clone();
+
+        let credentials = match (user, pw, token) {
+            (Some(username), Some(password), _) => Some(MetricsCredentials::Basic { username, password }),
+            (_, _, Some(token)) => Some(MetricsCredentials::Bearer(token)),
+            (_, _, _) => None,
+        };
+        Self { credentials }
+    }
+}
+
+/// Compare secrets without bailing out at the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl MetricsCredentials {
+    fn matches(&self, header: &str) -> bool {
+        match self {
+            MetricsCredentials::Basic { username, password } => {
+                if !header.starts_with("Basic ") {
+                    return false;
+                }
+                let expected = format!("{}:{}", username, password);
+                base64::decode(&header["Basic ".len()..])
+                    .map(|given| constant_time_eq(&given, expected.as_bytes()))
+                    .unwrap_or(false)
+            },
+            MetricsCredentials::Bearer(token) => {
+                header.starts_with("Bearer ")
+                    && constant_time_eq(header["Bearer ".len()..].as_bytes(), token.as_bytes())
+            },
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        match self {
+            MetricsCredentials::Basic { .. } => "Basic realm=\"metrics\"",
+            MetricsCredentials::Bearer(_) => "Bearer realm=\"metrics\"",
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> Middleware<T> for MetricsAuth {
+    fn handle<'a>(&'a self, cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
+        FutureExt::boxed(async move {
+            let credentials = match &self.credentials {
+                Some(c) => c,
+                None => return next.run(cx).await,
+            };
+
+            let authorized = cx.headers()
+                .get(http::header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| credentials.matches(v))
+                .unwrap_or(false);
+            if authorized {
+                return next.run(cx).await;
+            }
+
+            http::Response::builder()
+                .status(http::StatusCode::UNAUTHORIZED)
+                .header(http::header::WWW_AUTHENTICATE, credentials.challenge())
+                .header(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body("Unauthorized.\n".into())
+                .unwrap()
+        })
+    }
+}
+
+
 pub struct BearerAuth {
     pub db: Database
 }
 
-impl Default for BearerAuth {
-    fn default() -> Self {
-        Self {
-            db: Database::new(),
-        }
+impl BearerAuth {
+    pub fn new(db: Database) -> Self {
+        Self { db }
     }
 }
 
 fn unauthorized() -> http::Response<http_service::Body> {
-    http::Response::builder()
-        .status(http::StatusCode::UNAUTHORIZED)
-        .header(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")
-        .body("Invalid bearer token.\n".into())
-        .unwrap()
+    let mut res = ApiError::new(ErrorCode::Unauthorized, "Invalid bearer token").into_response();
+    res.headers_mut().insert(http::header::WWW_AUTHENTICATE, http::header::HeaderValue::from_static("Bearer"));
+    res
 }
 
 impl<T: Send + Sync + 'static> Middleware<T> for BearerAuth {
-    fn handle<'a>(&'a self, cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
+    fn handle<'a>(&'a self, mut cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
         FutureExt::boxed(async move {
             let path = cx.uri();
 
-            if path.path().contains("bearer_tokens") {
+            if path.path().contains("bearer_tokens") || path.path().contains("admin_tokens") {
                 return unauthorized();
             }
 
-            if !path.path().starts_with("/api") {
+            if !path.path().starts_with("/api") || path.path() == openapi::DOCUMENT_PATH {
                 return next.run(cx).await
             }
 
@@ -134,9 +513,16 @@ impl<T: Send + Sync + 'static> Middleware<T> for BearerAuth {
                     return unauthorized();
                 }
                 let token = val.replace("Bearer ", "");
-                if !self.db.clone().validate_token(token).await {
+                if !self.db.clone().validate_token(token.clone()).await {
                     return unauthorized();
                 }
+                // Admin tokens are a subset of the regular bearer tokens.
+                let role = if self.db.clone().is_admin_token(token).await {
+                    TokenRole::Admin
+                } else {
+                    TokenRole::Author
+                };
+                cx.extensions_mut().insert(role);
                 next.run(cx).await
             } else {
                 return unauthorized();
@@ -145,3 +531,361 @@ impl<T: Send + Sync + 'static> Middleware<T> for BearerAuth {
         })
     }
 }
+
+
+/// Whether a path is under either API root.
+fn is_api(path: &str) -> bool {
+    path == openapi::LEGACY_API_ROOT || path.starts_with(&format!("{}/", openapi::LEGACY_API_ROOT))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum LimitClass {
+    Public,
+    Api,
+    AuthFailure,
+}
+
+impl LimitClass {
+    fn label(self) -> &'static str {
+        match self {
+            LimitClass::Public => "public",
+            LimitClass::Api => "api",
+            LimitClass::AuthFailure => "auth_failure",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiting, kept in memory, so each server enforces its
+/// limits separately. Pages are limited per client address and the API per
+/// bearer token, while failed token checks are limited per address to slow
+/// down guessing. Requests whose address `TrustedProxies` couldn't resolve
+/// skip the per-address limits rather than all sharing one bucket.
+pub struct RateLimit {
+    config: RateLimitConfig,
+    buckets: Mutex<LruCache<(LimitClass, String), Bucket>>,
+}
+
+impl RateLimit {
+    pub fn new(config: &RateLimitConfig, proxy: &ProxyConfig) -> Self {
+        if config.enabled && proxy.trusted.is_empty() {
+            eprintln!("No proxy.trusted configured, so client addresses are unknown and only the per-token API rate limit applies");
+        }
+        Self {
+            config: config.clone(),
+            buckets: Mutex::new(LruCache::new(config.max_clients)),
+        }
+    }
+
+    fn limit(&self, class: LimitClass) -> Limit {
+        match class {
+            LimitClass::Public => self.config.public,
+            LimitClass::Api => self.config.api,
+            LimitClass::AuthFailure => self.config.auth_failure,
+        }
+    }
+
+    /// Check a client's bucket has a token, taking it if `take` is set.
+    /// Otherwise returns how long until it will have one.
+    fn bucket(&self, class: LimitClass, key: &str, take: bool) -> Result<(), Duration> {
+        let limit = self.limit(class);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let id = (class, key.to_string());
+        let mut bucket = buckets.get(&id).cloned().unwrap_or(Bucket {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(f64::from(limit.burst));
+        bucket.updated = now;
+
+        let res = if bucket.tokens >= 1.0 {
+            if take {
+                bucket.tokens -= 1.0;
+            }
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate))
+        };
+        buckets.put(id, bucket);
+        RATE_LIMIT_CLIENTS.set(buckets.len() as i64);
+        res
+    }
+
+}
+
+/// Identifies a bearer token without keeping the token itself around.
+fn token_id(headers: &http::HeaderMap) -> Option<String> {
+    let token = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let digest = format!("{:x}", Sha256::digest(token.as_bytes()));
+    Some(format!("token:{}", &digest[..16]))
+}
+
+fn too_many_requests(class: LimitClass, wait: Duration, api: bool) -> Response {
+    RATE_LIMITED.with_label_values(&[class.label()]).inc();
+    let secs = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+    let mut res = if api {
+        ApiError::new(ErrorCode::TooManyRequests, "Too many requests")
+            .with_details(serde_json::json!({ "retry_after": secs }))
+            .into_response()
+    } else {
+        http::Response::builder()
+            .status(http::StatusCode::TOO_MANY_REQUESTS)
+            .header(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body("Too many requests.\n".into())
+            .unwrap()
+    };
+    res.headers_mut().insert(http::header::RETRY_AFTER, http::header::HeaderValue::from(secs));
+    res
+}
+
+impl<T: Send + Sync + 'static> Middleware<T> for RateLimit {
+    fn handle<'a>(&'a self, cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
+        FutureExt::boxed(async move {
+            let path = cx.uri().path();
+            if !self.config.enabled || path == "/_health" || path.starts_with("/static/") {
+                return next.run(cx).await;
+            }
+            let ip = ClientInfo::of(&cx).ip.map(|ip| ip.to_string());
+
+            if !is_api(path) {
+                if let Some(ip) = &ip {
+                    if let Err(wait) = self.bucket(LimitClass::Public, ip, true) {
+                        return too_many_requests(LimitClass::Public, wait, false);
+                    }
+                }
+                return next.run(cx).await;
+            }
+
+            if let Some(ip) = &ip {
+                if let Err(wait) = self.bucket(LimitClass::AuthFailure, ip, false) {
+                    return too_many_requests(LimitClass::AuthFailure, wait, true);
+                }
+            }
+            if let Some(key) = token_id(cx.headers()).or_else(|| ip.clone()) {
+                if let Err(wait) = self.bucket(LimitClass::Api, &key, true) {
+                    return too_many_requests(LimitClass::Api, wait, true);
+                }
+            }
+
+            let res = next.run(cx).await;
+            if let Some(ip) = &ip {
+                if res.status() == http::StatusCode::UNAUTHORIZED {
+                    let _ = self.bucket(LimitClass::AuthFailure, ip, true);
+                }
+            }
+            res
+        })
+    }
+}
+
+/// Marks responses from the unversioned API root as deprecated, pointing
+/// at the same resource under the current root.
+pub struct ApiDeprecation;
+
+impl<T: Send + Sync + 'static> Middleware<T> for ApiDeprecation {
+    fn handle<'a>(&'a self, cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
+        FutureExt::boxed(async move {
+            let path = cx.uri().path();
+            let root = openapi::LEGACY_API_ROOT;
+            let current = path == openapi::API_ROOT || path.starts_with(&format!("{}/", openapi::API_ROOT));
+            let legacy = if path.starts_with(&format!("{}/", root)) && !current {
+                Some(path[root.len()..].to_string())
+            } else {
+                None
+            };
+
+            let mut res = next.run(cx).await;
+            if let Some(rest) = legacy {
+                let headers = res.headers_mut();
+                headers.insert("deprecation", http::header::HeaderValue::from_static("true"));
+                let link = format!("<{}{}>; rel=\"successor-version\"", openapi::API_ROOT, rest);
+                if let Ok(link) = http::header::HeaderValue::from_str(&link) {
+                    headers.insert(http::header::LINK, link);
+                }
+            }
+            res
+        })
+    }
+}
+
+
+/// Content encodings supported by `Compression`, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut w = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                w.write_all(body)?;
+                Ok(w.into_inner())
+            },
+            Encoding::Gzip => {
+                let mut w = GzEncoder::new(Vec::new(), Level::default());
+                w.write_all(body)?;
+                w.finish()
+            },
+            // HTTP's "deflate" is the zlib format, not a raw deflate stream.
+            Encoding::Deflate => {
+                let mut w = ZlibEncoder::new(Vec::new(), Level::default());
+                w.write_all(body)?;
+                w.finish()
+            },
+        }
+    }
+}
+
+/// Choose the encoding with the highest q-value from an `Accept-Encoding`
+/// header, falling back to our own preference order on ties.
+fn negotiate(accept: &str) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut explicit: Vec<(&str, f32)> = vec![];
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(|p| p.trim());
+        let name = parts.next().unwrap_or_default();
+        let q = parts
+            .find(|p| p.starts_with("q="))
+            .and_then(|p| p[2..].parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(q);
+        } else {
+            explicit.push((name, q));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for enc in &[Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let q = explicit.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(enc.name()))
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        if q > 0.0 && best.map_or(true, |(_, b)| q > b) {
+            best = Some((*enc, q));
+        }
+    }
+    best.map(|(enc, _)| enc)
+}
+
+/// Media types worth compressing. Most others are already compressed, where
+/// another pass only costs CPU, and event streams have to reach the client as
+/// they're written rather than once they end.
+fn is_compressible(content_type: &str) -> bool {
+    let ct = content_type.split(';').next().unwrap_or_default().trim();
+    if ct == "image/svg+xml" {
+        return true;
+    }
+    !(ct.starts_with("image/")
+        || ct == "text/event-stream"
+        || ct.starts_with("video/")
+        || ct.starts_with("audio/")
+        || ct.starts_with("font/woff")
+        || ct == "application/zip"
+        || ct == "application/gzip"
+        || ct == "application/octet-stream")
+}
+
+/// Compresses response bodies with brotli, gzip or deflate based on the
+/// request's `Accept-Encoding` header.
+pub struct Compression {
+    /// Bodies smaller than this are sent as-is.
+    pub min_size: usize,
+}
+
+impl Compression {
+    pub fn new(min_size: usize) -> Self {
+        Self { min_size }
+    }
+}
+
+impl<T: Send + Sync + 'static> Middleware<T> for Compression {
+    fn handle<'a>(&'a self, cx: Context<T>, next: Next<'a, T>) -> BoxFuture<'a, Response> {
+        FutureExt::boxed(async move {
+            let encoding = cx.headers()
+                .get(http::header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .and_then(negotiate);
+            let head = cx.method() == http::Method::HEAD;
+
+            let mut res = next.run(cx).await;
+
+            let status = res.status();
+            let headers = res.headers();
+            if status == http::StatusCode::NO_CONTENT
+                || headers.contains_key(http::header::CONTENT_ENCODING)
+            {
+                return res;
+            }
+            let content_type = headers.get(http::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("text/plain");
+            if !is_compressible(content_type) {
+                return res;
+            }
+
+            // The representation depends on Accept-Encoding whatever the
+            // body's size, so small bodies, HEADs and 304s say so too, and
+            // every client accepting an encoding gets that encoding's ETag.
+            let headers = res.headers_mut();
+            headers.append(
+                http::header::VARY,
+                http::header::HeaderValue::from_static("Accept-Encoding"),
+            );
+            let encoding = match encoding {
+                Some(e) => e,
+                None => return res,
+            };
+            let etag = headers.get(http::header::ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(|etag| http_cache::encoded_etag(etag, encoding.name()));
+            if let Some(etag) = etag.and_then(|e| http::header::HeaderValue::from_str(&e).ok()) {
+                headers.insert(http::header::ETAG, etag);
+            }
+            if head || status == http::StatusCode::NOT_MODIFIED {
+                return res;
+            }
+
+            let (mut parts, body) = res.into_parts();
+            let body = match body.into_vec().await {
+                Ok(body) => body,
+                Err(_) => return http::Response::from_parts(parts, Vec::new().into()),
+            };
+            if body.len() < self.min_size {
+                return http::Response::from_parts(parts, body.into());
+            }
+
+            match encoding.encode(&body) {
+                Ok(compressed) => {
+                    parts.headers.insert(
+                        http::header::CONTENT_ENCODING,
+                        http::header::HeaderValue::from_static(encoding.name()),
+                    );
+                    parts.headers.remove(http::header::CONTENT_LENGTH);
+                    http::Response::from_parts(parts, compressed.into())
+                },
+                Err(_) => http::Response::from_parts(parts, body.into()),
+            }
+        })
+    }
+}
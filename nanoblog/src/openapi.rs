@@ -0,0 +1,622 @@
+use futures::future::BoxFuture;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use serde_json::{json, Map, Value};
+use tide::{http, Context, EndpointResult};
+
+use crate::batch;
+use crate::db::{self, Database};
+use crate::error::{ApiError, ErrorCode};
+use crate::middleware::ClientInfo;
+
+/// Current API root. The unversioned `/api` root is a deprecated alias.
+pub const API_ROOT: &str = "/api/v1";
+pub const LEGACY_API_ROOT: &str = "/api";
+/// Served without a bearer token.
+pub const DOCUMENT_PATH: &str = "/api/v1/openapi.json";
+
+pub type Handler = fn(Context<Database>) -> BoxFuture<'static, EndpointResult>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    Patch,
+    Delete,
+}
+
+impl Method {
+    fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "get",
+            Method::Post => "post",
+            Method::Patch => "patch",
+            Method::Delete => "delete",
+        }
+    }
+}
+
+/// What an operation takes as its request body.
+#[derive(Debug, Clone, Copy)]
+pub enum Body {
+    /// JSON matching the named schema.
+    Json(&'static str),
+    /// A JSON merge patch matching the named schema.
+    MergePatch(&'static str),
+    /// Any bytes, e.g. a file upload.
+    Binary,
+}
+
+/// One API endpoint. Routes are registered from these, and the OpenAPI
+/// document is built from the same list, so the two can't drift apart.
+pub struct Operation {
+    pub id: &'static str,
+    pub method: Method,
+    /// Path below the API root, in router syntax.
+    pub path: &'static str,
+    pub summary: &'static str,
+    /// Query parameters, as name and description.
+    pub query: &'static [(&'static str, &'static str)],
+    pub request: Option<Body>,
+    pub status: u16,
+    /// Schema of the JSON response, if there is one.
+    pub response: Option<&'static str>,
+    /// Media types offered besides JSON, through `Accept`.
+    pub alternates: &'static [&'static str],
+    pub errors: &'static [ErrorCode],
+    pub handler: Handler,
+}
+
+impl Operation {
+    /// The path with `:param` segments written as `{param}`.
+    fn openapi_path(&self) -> String {
+        self.path.split('/')
+            .map(|s| if s.starts_with(':') { format!("{{{}}}", &s[1..]) } else { s.to_string() })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn path_params(&self) -> Vec<&'static str> {
+        self.path.split('/')
+            .filter(|s| s.starts_with(':'))
+            .map(|s| &s[1..])
+            .collect()
+    }
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Schemas for every type the API sends or receives, generated from the
+/// types themselves and named after them.
+fn schemas() -> Value {
+    let mut gen = SchemaGenerator::new(SchemaSettings::openapi3());
+    // List responses are plain arrays, named here so operations can refer
+    // to them.
+    let lists = vec![
+        ("PostList", "Posts ordered newest first.", gen.subschema_for::<Vec<db::PostSummary>>()),
+        ("PostRevisionList", "Revisions ordered newest first.", gen.subschema_for::<Vec<db::PostRevision>>()),
+        ("WebhookDeliveryList", "Delivery attempts ordered newest first.", gen.subschema_for::<Vec<db::WebhookDelivery>>()),
+    ];
+    gen.subschema_for::<db::NewPost>();
+    gen.subschema_for::<db::PostPatch>();
+    gen.subschema_for::<db::BatchRequest>();
+    gen.subschema_for::<db::BatchResponse>();
+    gen.subschema_for::<db::Asset>();
+    gen.subschema_for::<db::ChangeList>();
+    gen.subschema_for::<ApiError>();
+
+    let mut schemas = serde_json::to_value(gen.definitions()).unwrap_or_default();
+    for (name, description, schema) in lists {
+        let mut schema = serde_json::to_value(schema).unwrap_or_default();
+        schema["description"] = description.into();
+        schemas[name] = schema;
+    }
+    // Enforced by the handler rather than the type.
+    schemas["BatchRequest"]["properties"]["operations"]["maxItems"] = batch::MAX_OPERATIONS.into();
+    schemas
+}
+
+/// Errors any operation can fail with, from the middleware in front of it.
+const COMMON_CODES: &[ErrorCode] = &[ErrorCode::Unauthorized, ErrorCode::TooManyRequests];
+
+fn operation(op: &Operation) -> Value {
+    let mut parameters: Vec<Value> = op.path_params().iter()
+        .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+        .collect();
+    parameters.extend(op.query.iter().map(|(name, description)| json!({
+        "name": name,
+        "in": "query",
+        "description": description,
+        "schema": { "type": "string" },
+    })));
+
+    let mut content = Map::new();
+    if let Some(schema) = op.response {
+        content.insert("application/json".into(), json!({ "schema": schema_ref(schema) }));
+    }
+    for media in op.alternates {
+        content.insert((*media).into(), json!({ "schema": { "type": "string" } }));
+    }
+    let mut success = json!({ "description": op.summary });
+    if !content.is_empty() {
+        success["content"] = Value::Object(content);
+    }
+
+    let mut responses = Map::new();
+    responses.insert(op.status.to_string(), success);
+    let mut statuses: Vec<u16> = op.errors.iter()
+        .chain(COMMON_CODES)
+        .map(|c| c.status().as_u16())
+        .collect();
+    statuses.sort();
+    statuses.dedup();
+    for status in statuses {
+        let codes: Vec<&str> = op.errors.iter()
+            .chain(COMMON_CODES)
+            .filter(|c| c.status().as_u16() == status)
+            .map(|c| c.as_str())
+            .collect();
+        responses.insert(status.to_string(), json!({
+            "description": codes.join(", "),
+            "content": { "application/json": { "schema": schema_ref("ApiError") } },
+        }));
+    }
+
+    let mut value = json!({
+        "operationId": op.id,
+        "summary": op.summary,
+        "parameters": parameters,
+        "responses": responses,
+    });
+    match op.request {
+        Some(Body::Json(schema)) => {
+            value["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema_ref(schema) } },
+            });
+        },
+        Some(Body::MergePatch(schema)) => {
+            value["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    "application/merge-patch+json": { "schema": schema_ref(schema) },
+                    "application/json": { "schema": schema_ref(schema) },
+                },
+            });
+        },
+        Some(Body::Binary) => {
+            value["requestBody"] = json!({
+                "required": true,
+                "content": { "*/*": { "schema": { "type": "string", "format": "binary" } } },
+            });
+        },
+        None => {},
+    }
+    value
+}
+
+/// The OpenAPI 3 description of `ops`, served from `server`.
+pub fn document(ops: &[Operation], server: &str) -> Value {
+    let mut paths = Map::new();
+    for op in ops {
+        let path = paths.entry(op.openapi_path())
+            .or_insert_with(|| Value::Object(Map::new()));
+        path[op.method.as_str()] = operation(op);
+    }
+
+    json!({
+        "openapi": "3.0.2",
+        "info": {
+            "title": "nanoblog",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": server }],
+        "security": [{ "bearer": [] }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    })
+}
+
+/// Serve the document, with the API's absolute URL as its server when the
+/// request's host is known.
+pub async fn serve(cx: Context<Database>) -> EndpointResult {
+    let server = ClientInfo::of(&cx).url(API_ROOT).unwrap_or_else(|| API_ROOT.to_string());
+    let body = serde_json::to_string_pretty(&document(&crate::api::operations(), &server))
+        .unwrap_or_default();
+    let res = http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .expect("Error building response");
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::ops::Deref;
+
+    use futures::TryFutureExt;
+    use futures::executor::block_on;
+    use http_service::HttpService;
+    use r2d2_redis::redis;
+    use tide::http::header;
+
+    use super::*;
+    use crate::{api, middleware};
+
+    const TOKEN: &str = "openapi-test-token";
+
+    fn resolve<'a>(doc: &'a Value, schema: &'a Value) -> &'a Value {
+        match schema["$ref"].as_str() {
+            Some(r) => resolve(doc, &doc["components"]["schemas"][r.trim_start_matches("#/components/schemas/")]),
+            None => schema,
+        }
+    }
+
+    /// Check `value` against the parts of JSON Schema the generated schemas
+    /// use, naming the first place it doesn't match.
+    fn conforms(doc: &Value, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        let schema = resolve(doc, schema);
+        if schema.is_null() {
+            return Err(format!("{} refers to a schema that doesn't exist", at));
+        }
+        if value.is_null() && schema["nullable"] == true {
+            return Ok(());
+        }
+        for s in schema["allOf"].as_array().into_iter().flatten() {
+            conforms(doc, s, value, at)?;
+        }
+        for key in &["anyOf", "oneOf"] {
+            if let Some(choices) = schema[*key].as_array() {
+                if !choices.iter().any(|s| conforms(doc, s, value, at).is_ok()) {
+                    return Err(format!("{} matches none of its {} schemas: {}", at, key, value));
+                }
+            }
+        }
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                return Err(format!("{} is {}, which isn't one of {:?}", at, value, allowed));
+            }
+        }
+        let typed = match schema["type"].as_str() {
+            Some("object") => value.is_object(),
+            Some("array") => value.is_array(),
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_u64() || value.is_i64(),
+            Some("number") => value.is_number(),
+            Some("boolean") => value.is_boolean(),
+            _ => true,
+        };
+        if !typed {
+            return Err(format!("{} should be of type {}: {}", at, schema["type"], value));
+        }
+        for field in schema["required"].as_array().into_iter().flatten() {
+            let field = field.as_str().unwrap_or_default();
+            if value.get(field).is_none() {
+                return Err(format!("{} is missing required field {}", at, field));
+            }
+        }
+        if let (Some(properties), Some(fields)) = (schema["properties"].as_object(), value.as_object()) {
+            for (name, field) in fields {
+                let property = properties.get(name)
+                    .ok_or_else(|| format!("{} has undocumented field {}", at, name))?;
+                conforms(doc, property, field, &format!("{}.{}", at, name))?;
+            }
+        }
+        if let (Some(items), Some(values)) = (schema.get("items"), value.as_array()) {
+            for (i, item) in values.iter().enumerate() {
+                conforms(doc, items, item, &format!("{}[{}]", at, i))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn operation<'a>(doc: &'a Value, id: &str) -> &'a Value {
+        doc["paths"].as_object().into_iter()
+            .flat_map(|paths| paths.values())
+            .flat_map(|path| path.as_object().into_iter().flat_map(|p| p.values()))
+            .find(|op| op["operationId"] == id)
+            .unwrap_or_else(|| panic!("{} isn't documented", id))
+    }
+
+    /// Check a response from operation `id` is one the document describes,
+    /// down to the fields of its body.
+    fn documented(doc: &Value, id: &str, status: u16, content_type: Option<&str>, body: &[u8]) -> Result<(), String> {
+        let response = &operation(doc, id)["responses"][&status.to_string()];
+        if response.is_null() {
+            return Err(format!("{} responded {}, which isn't documented", id, status));
+        }
+        let media = match content_type {
+            Some(t) => t.split(';').next().unwrap_or_default().trim(),
+            None if body.is_empty() && response.get("content").is_none() => return Ok(()),
+            None => return Err(format!("{} {} has no Content-Type", id, status)),
+        };
+        let content = &response["content"][media];
+        if content.is_null() {
+            return Err(format!("{} {} is {}, which isn't documented", id, status, media));
+        }
+        if media == "application/json" {
+            let value: Value = serde_json::from_slice(body)
+                .map_err(|e| format!("{} {} isn't JSON: {}", id, status, e))?;
+            conforms(doc, &content["schema"], &value, id)?;
+        }
+        Ok(())
+    }
+
+    fn value<T: serde::Serialize>(value: &T) -> Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    /// A value of each schema, filling in the optional fields.
+    fn samples() -> Vec<(&'static str, Value)> {
+        let post = db::Post {
+            slug: "hello-world".into(),
+            title: "Hello world".into(),
+            body: "Hi".into(),
+            date_created: "2019-06-01".into(),
+            date_updated: Some("2019-06-02".into()),
+            updated_at: Some("2019-06-02T10:00:00+00:00".into()),
+            trusted_html: false,
+            revision: 2,
+            tags: vec!["rust".into()],
+        };
+        let variant = db::Variant {
+            hash: "ab".into(),
+            name: "photo-480w.webp".into(),
+            content_type: "image/webp".into(),
+            width: 480,
+            height: 320,
+        };
+        let asset = db::Asset {
+            hash: "cd".into(),
+            name: "photo.jpg".into(),
+            content_type: "image/jpeg".into(),
+            size: 1024,
+            width: Some(960),
+            height: Some(640),
+            variants: vec![variant],
+        };
+        let new_post = db::NewPost {
+            title: "Hello world".into(),
+            body: "Hi".into(),
+            trusted_html: false,
+            tags: Some(vec![]),
+        };
+        let error = ApiError::bad_request("Nope").with_details(json!({ "field": "title" }));
+        let operations = vec![
+            db::BatchOperation::Upsert {
+                post: new_post.clone(),
+                draft: true,
+                date_created: Some("2019-06-01".into()),
+                slug: Some("hello".into()),
+            },
+            db::BatchOperation::Publish { slug: "hello".into() },
+            db::BatchOperation::Unpublish { slug: "hello".into() },
+            db::BatchOperation::Delete { slug: "hello".into() },
+        ];
+        let results = vec![
+            db::BatchItemResult { op: "upsert".into(), slug: "hello".into(), status: 200, post: Some(post.clone()), error: None },
+            db::BatchItemResult { op: "delete".into(), slug: "gone".into(), status: 404, post: None, error: Some(error.clone()) },
+        ];
+        let change = db::Change { seq: 1, kind: db::ChangeKind::AssetSaved, id: "cd".into(), at: "2019-06-01T00:00:00+00:00".into() };
+        let delivery = db::WebhookDelivery {
+            id: "d1".into(),
+            subscription: "site".into(),
+            event: db::WebhookEvent::PostDeleted,
+            payload: json!({ "slug": "hello" }),
+            created_at: "2019-06-01T00:00:00+00:00".into(),
+            status: db::DeliveryStatus::Failed,
+            attempts: 3,
+            last_attempt_at: Some("2019-06-01T00:01:00+00:00".into()),
+            response_status: Some(500),
+            error: Some("The receiver responded with 500".into()),
+            next_attempt_at: None,
+        };
+        let patch = db::PostPatch {
+            title: Some("Hello".into()),
+            tags: Some(vec![]),
+            draft: Some(false),
+            ..db::PostPatch::default()
+        };
+
+        vec![
+            ("NewPost", value(&new_post)),
+            ("Post", value(&post)),
+            ("PostPatch", value(&patch)),
+            ("PostPatch", json!({ "tags": null, "trusted_html": null })),
+            ("Asset", value(&asset)),
+            ("PostList", value(&vec![db::PostSummary::new(post.into(), db::PostStatus::Scheduled)])),
+            ("PostRevisionList", value(&vec![db::PostRevision { revision: 2, date: "2019-06-02".into(), changes: json!({ "body": "Hi" }) }])),
+            ("BatchRequest", value(&db::BatchRequest { mode: db::BatchMode::BestEffort, operations })),
+            ("BatchResponse", value(&db::BatchResponse { mode: db::BatchMode::Atomic, committed: false, results })),
+            ("ChangeList", value(&db::ChangeList { changes: vec![change], next: 2, more: true })),
+            ("WebhookDeliveryList", value(&vec![delivery])),
+            ("ApiError", value(&error)),
+        ]
+    }
+
+    /// Make one request of the app with the admin token.
+    fn send<S: HttpService>(
+        server: &S,
+        method: &str,
+        uri: &str,
+        content_type: Option<&str>,
+        accept: Option<&str>,
+        body: &str,
+    ) -> (u16, Option<String>, Vec<u8>) {
+        let mut req = http::Request::builder();
+        req.method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN));
+        if let Some(content_type) = content_type {
+            req.header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(accept) = accept {
+            req.header(header::ACCEPT, accept);
+        }
+        let req = req.body(body.as_bytes().to_vec().into()).unwrap();
+
+        let mut conn = block_on(server.connect().into_future())
+            .unwrap_or_else(|_| panic!("Can't connect to the app"));
+        let res = block_on(server.respond(&mut conn, req).into_future())
+            .unwrap_or_else(|_| panic!("{} {} failed", method, uri));
+        let status = res.status().as_u16();
+        let content_type = res.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let body = block_on(res.into_body().into_vec()).unwrap();
+        (status, content_type, body)
+    }
+
+    #[test]
+    fn schemas_match_what_the_types_serialize() {
+        let doc = document(&api::operations(), API_ROOT);
+        let schemas = &doc["components"]["schemas"];
+        for (name, sample) in samples() {
+            conforms(&doc, &schemas[name], &sample, name).unwrap();
+        }
+
+        let post = json!({ "title": "Hello world", "body": "Hi", "date_created": "2019-06-01", "date_updated": null });
+        let missing = conforms(&doc, &schemas["Post"], &post, "Post").unwrap_err();
+        assert!(missing.contains("slug"), "{}", missing);
+        let nested = json!({
+            "mode": "atomic",
+            "committed": true,
+            "results": [{ "op": "upsert", "slug": "hello-world", "status": 200, "post": post }],
+        });
+        assert!(conforms(&doc, &schemas["BatchResponse"], &nested, "BatchResponse").is_err());
+        let extra = json!({ "code": "not_found", "message": "Gone", "hint": "" });
+        assert!(conforms(&doc, &schemas["ApiError"], &extra, "ApiError").is_err());
+
+        for status in &[db::PostStatus::Published, db::PostStatus::Scheduled, db::PostStatus::Draft] {
+            conforms(&doc, &schemas["PostStatus"], &value(status), "PostStatus").unwrap();
+        }
+        for status in &[db::DeliveryStatus::Pending, db::DeliveryStatus::Delivered, db::DeliveryStatus::Failed] {
+            conforms(&doc, &schemas["DeliveryStatus"], &value(status), "DeliveryStatus").unwrap();
+        }
+        for kind in db::ChangeKind::ALL {
+            conforms(&doc, &schemas["ChangeKind"], &value(kind), "ChangeKind").unwrap();
+        }
+        for event in db::WebhookEvent::ALL {
+            conforms(&doc, &schemas["WebhookEvent"], &value(event), "WebhookEvent").unwrap();
+        }
+        for op in api::operations() {
+            for code in op.errors.iter().chain(COMMON_CODES) {
+                conforms(&doc, &schemas["ErrorCode"], &value(code), "ErrorCode").unwrap();
+            }
+        }
+
+        let mut patchable: BTreeSet<&str> = schemas["PostPatch"]["properties"].as_object().unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert!(patchable.remove("draft"));
+        assert_eq!(patchable, api::PATCHABLE.iter().cloned().collect::<BTreeSet<_>>());
+        assert_eq!(schemas["BatchRequest"]["properties"]["operations"]["maxItems"], batch::MAX_OPERATIONS);
+    }
+
+    #[test]
+    fn every_operation_is_documented() {
+        let ops = api::operations();
+        let doc = document(&ops, API_ROOT);
+        let mut routes = BTreeSet::new();
+        for op in &ops {
+            assert!(routes.insert((op.method.as_str(), op.path)), "{} {} is registered twice", op.method.as_str(), op.path);
+            let described = &doc["paths"][&op.openapi_path()][op.method.as_str()];
+            assert_eq!(described["operationId"], op.id);
+
+            let success = &described["responses"][&op.status.to_string()];
+            let media: BTreeSet<&str> = success["content"].as_object().into_iter()
+                .flat_map(|content| content.keys())
+                .map(String::as_str)
+                .collect();
+            let expected: BTreeSet<&str> = op.response.map(|_| "application/json").into_iter()
+                .chain(op.alternates.iter().cloned())
+                .collect();
+            assert_eq!(media, expected, "{}", op.id);
+            let schemas = [
+                &success["content"]["application/json"]["schema"],
+                &described["requestBody"]["content"]["application/json"]["schema"],
+            ];
+            for schema in &schemas {
+                if !schema.is_null() {
+                    assert!(!resolve(&doc, schema).is_null(), "{} refers to a missing schema", op.id);
+                }
+            }
+            for code in op.errors.iter().chain(COMMON_CODES) {
+                let status = code.status().as_u16().to_string();
+                assert!(described["responses"][&status]["description"].as_str().unwrap().contains(code.as_str()));
+            }
+        }
+
+        let asset = samples().into_iter().find(|(name, _)| *name == "Asset").unwrap().1.to_string();
+        documented(&doc, "uploadAsset", 201, Some("application/json"), asset.as_bytes()).unwrap();
+        assert!(documented(&doc, "uploadAsset", 200, Some("application/json"), asset.as_bytes()).is_err());
+        assert!(documented(&doc, "deletePost", 204, Some("application/json"), b"{}").is_err());
+    }
+
+    /// Runs every operation against Redis at `$NANOBLOG_TEST_REDIS` (by
+    /// default database 15 on localhost), which it flushes, and checks each
+    /// response against the served document.
+    #[test]
+    #[ignore]
+    fn handlers_respond_as_documented() {
+        let host = std::env::var("NANOBLOG_TEST_REDIS").unwrap_or_else(|_| "localhost/15".into());
+        let db = Database::new(Some(&host), None);
+        block_on(db.run(|conn| redis::cmd("FLUSHDB").query::<()>(conn.deref()))).unwrap();
+        block_on(db.add_token(TOKEN.into(), true)).unwrap();
+
+        let ops = api::operations();
+        let mut app = tide::App::new(db.clone());
+        app.middleware(middleware::BearerAuth::new(db));
+        crate::api_routes(&mut app, &ops);
+        let server = app.into_http_service();
+
+        let (status, content_type, body) = send(&server, "GET", DOCUMENT_PATH, None, None, "");
+        assert_eq!((status, content_type.as_ref().map(String::as_str)), (200, Some("application/json")));
+        let doc: Value = serde_json::from_slice(&body).unwrap();
+
+        let json = Some("application/json");
+        let cases = vec![
+            ("ping", "GET", "/ping", None, None, "", 200),
+            ("upsertPost", "POST", "/posts", json, None, r#"{"title": "Hello world", "body": "Hi", "tags": ["a"]}"#, 200),
+            ("upsertPost", "POST", "/posts", json, None, r#"{"title": "Hello world"}"#, 400),
+            ("listPosts", "GET", "/posts", None, None, "", 200),
+            ("getPost", "GET", "/posts/hello-world", None, None, "", 200),
+            ("getPost", "GET", "/posts/hello-world", None, Some("text/markdown"), "", 200),
+            ("patchPost", "PATCH", "/posts/hello-world", Some("application/merge-patch+json"), None, r#"{"body": "Changed", "draft": true}"#, 200),
+            ("listPostRevisions", "GET", "/posts/hello-world/revisions", None, None, "", 200),
+            (
+                "batchPosts", "POST", "/posts:batch", json, None,
+                r#"{"mode": "best_effort", "operations": [{"op": "publish", "slug": "hello-world"}, {"op": "delete", "slug": "missing"}]}"#,
+                200,
+            ),
+            ("unpublishPost", "POST", "/posts/hello-world/unpublish", None, None, "", 204),
+            ("unpublishPost", "POST", "/posts/missing/unpublish", None, None, "", 404),
+            ("uploadAsset", "POST", "/assets?name=notes.txt", Some("text/plain"), None, "Some notes", 201),
+            ("uploadAsset", "POST", "/assets", Some("text/plain"), None, "Some notes", 400),
+            ("listChanges", "GET", "/changes", None, None, "", 200),
+            ("listWebhookDeliveries", "GET", "/webhooks/deliveries", None, None, "", 200),
+            ("deletePost", "DELETE", "/posts/hello-world", None, None, "", 204),
+            ("getPost", "GET", "/posts/hello-world", None, None, "", 404),
+        ];
+        let mut covered = BTreeSet::new();
+        for (id, method, path, content_type, accept, body, expected) in cases {
+            let uri = format!("{}{}", API_ROOT, path);
+            let (status, returned, body) = send(&server, method, &uri, content_type, accept, body);
+            assert_eq!(status, expected, "{} {}: {}", method, uri, String::from_utf8_lossy(&body));
+            if let Err(e) = documented(&doc, id, status, returned.as_ref().map(String::as_str), &body) {
+                panic!("{} {}: {}", method, uri, e);
+            }
+            covered.insert(id);
+        }
+        assert_eq!(covered, ops.iter().map(|op| op.id).collect::<BTreeSet<_>>());
+    }
+}
@@ -1,24 +1,33 @@
-use tera::Tera;
+use std::collections::HashMap;
+
+use chrono::Local;
 use tide::http;
 use tide::{Context, EndpointResult, Error, error::ResultExt};
 
+use crate::cache;
+use crate::config;
 use crate::db;
+use crate::http_cache::{self, RouteClass, Validators};
+use crate::markdown;
+use crate::middleware;
+use crate::sanitize;
+use crate::templates;
 use http::status::StatusCode;
-use pulldown_cmark::{Parser, Options, html};
 
 const INDEX: &str = "index.html";
 const POST: &str = "post.html";
 const NOT_FOUND: &str = "404.html";
 
-lazy_static! {
-    pub static ref TERA: Tera =
-    { compile_templates!(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*.html")) };
+/// A template context holding the values every page needs.
+fn base_context() -> tera::Context {
+    let mut tera_ctx = tera::Context::new();
+    tera_ctx.insert("site", &config::get().site);
+    tera_ctx
 }
 
-
 pub fn res_404() -> EndpointResult {
-    let tera_ctx: tera::Context = tera::Context::new();
-    let body = TERA.render(NOT_FOUND, &tera_ctx).unwrap();
+    let tera_ctx = base_context();
+    let body = templates::render(NOT_FOUND, &tera_ctx).unwrap();
     let resp = http::Response::builder()
         .status(StatusCode::NOT_FOUND)
         .header(http::header::CONTENT_TYPE, "text/html; charset=UTF-8")
@@ -27,11 +36,23 @@ pub fn res_404() -> EndpointResult {
     Ok(resp)
 }
 
+/// A page that couldn't be read from storage. Unlike a 404, caches shouldn't
+/// hold on to it.
+fn res_503(e: &str) -> EndpointResult {
+    eprintln!("Storage unavailable: {}", e);
+    let resp = http::Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(http::header::CONTENT_TYPE, "text/html; charset=UTF-8")
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .body("<html><body>Service unavailable</body></html>".into())
+        .unwrap();
+    Ok(resp)
+}
 
-/// Render will apply the tera template context to the template and wrap it in a
-/// EndpointResult
-fn render(template: &str, tera_ctx: tera::Context) -> EndpointResult {
-    let body = TERA.render(template, &tera_ctx)
+/// Render will apply the tera template context to the template, producing
+/// the page body
+fn render_page(template: &str, tera_ctx: tera::Context) -> Result<String, Error> {
+    templates::render(template, &tera_ctx)
         .map_err(|e| {
             let resp = http::Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -39,8 +60,11 @@ fn render(template: &str, tera_ctx: tera::Context) -> EndpointResult {
                 .body(format!("<html><body>{:?}</body></html>", e).into())
                 .expect("Failed to build failure response");
             Error::from(resp)
-        })?;
+        })
+}
 
+/// Wrap a rendered page in an EndpointResult
+fn html_response(body: String) -> EndpointResult {
     let resp = http::Response::builder()
         .status(StatusCode::OK)
         .header(http::header::CONTENT_TYPE, "text/html; charset=UTF-8")
@@ -49,48 +73,114 @@ fn render(template: &str, tera_ctx: tera::Context) -> EndpointResult {
     Ok(resp)
 }
 
-/// Render markdown contents
-fn render_markdown(contents: &str) -> String {
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_STRIKETHROUGH);
-    options.insert(Options::ENABLE_TABLES);
-    let parser = Parser::new_ext(contents, options);
-    let mut html_output = String::new();
-    html::push_html(&mut html_output, parser);
-    html_output
-}
-
-
 pub async fn list_posts(cx: Context<db::Database>) -> EndpointResult {
     let client = cx.app_data().to_owned();
-    let mut tera_ctx: tera::Context = tera::Context::new();
+    let (revision, updated_at) = client.index_version().await;
+    let today = Local::today();
+    let v = Validators::page(revision, updated_at.as_ref().map(String::as_str)).as_of(today);
+    let today = today.format("%F").to_string();
+    let cached = cache::get(cache::INDEX_KEY, revision)
+        .filter(|page| page.rendered_on.as_ref() == Some(&today));
+    if let Some(page) = cached {
+        return Ok(http_cache::respond(cx.headers(), RouteClass::Index, &v, html_response(page.body)?));
+    }
+
+    let mut tera_ctx = base_context();
     let contents = client.list_posts().await;
 
+    // Scheduled posts stay off the index until their date.
     let mut contents: Vec<(String, db::PostMetadata)> = contents
         .into_iter()
+        .filter(|(_, p)| p.date_created <= today)
         .collect();
 
     contents.sort_by_key(|k| k.1.date_created.clone());
 
     tera_ctx.insert("post_links", &contents);
-    render(INDEX, tera_ctx)
+    let body = render_page(INDEX, tera_ctx)?;
+    cache::put(cache::INDEX_KEY, cache::CachedPage {
+        revision,
+        body: body.clone(),
+        updated_at: None,
+        rendered_on: Some(today),
+    });
+
+    Ok(http_cache::respond(cx.headers(), RouteClass::Index, &v, html_response(body)?))
+}
+
+
+/// Metadata of the hosted images a post embeds, for `render_post`.
+pub async fn post_assets(client: &db::Database, body: &str) -> HashMap<String, db::Asset> {
+    let mut assets = HashMap::new();
+    for hash in markdown::asset_hashes(body) {
+        if let Ok(Some(asset)) = client.get_asset(hash.clone()).await {
+            assets.insert(hash, asset);
+        }
+    }
+    assets
+}
+
+/// Run a post through the markdown pipeline, sanitizer and post template.
+pub fn render_post(contents: &db::Post, assets: &HashMap<String, db::Asset>) -> tera::Result<String> {
+    let rendered = markdown::render(&contents.body, assets);
+    let body = if contents.trusted_html {
+        rendered.html
+    } else {
+        sanitize::clean(&rendered.html)
+    };
+
+    let mut tera_ctx = base_context();
+    tera_ctx.insert("title", &contents.title);
+    tera_ctx.insert("date_created", &contents.date_created);
+    tera_ctx.insert("tags", &contents.tags);
+    tera_ctx.insert("body", &body);
+    tera_ctx.insert("toc", &rendered.toc);
+    templates::render(POST, &tera_ctx)
 }
 
 
 pub async fn get_post(cx: Context<db::Database>) -> EndpointResult {
     let client = cx.app_data().to_owned();
-    let mut tera_ctx: tera::Context = tera::Context::new();
 
     let title: String = cx.param("post").client_err()?;
 
-    let contents = client.get_post(title.clone()).await;
-    if contents.is_err() {
-        return res_404();
+    if *middleware::POST_VIEW_METRICS && client.is_published(title.clone()).await {
+        middleware::record_post_view(&title);
     }
-    let contents = contents.unwrap();
 
-    tera_ctx.insert("title", &contents.title);
-    tera_ctx.insert("date_created", &contents.date_created);
-    tera_ctx.insert("body", &render_markdown(&contents.body));
-    render(POST, tera_ctx)
+    let revision = client.get_revision(title.clone()).await;
+    if let Some(page) = cache::get(&title, revision) {
+        let v = Validators::page(revision, page.updated_at.as_ref().map(String::as_str));
+        return Ok(http_cache::respond(cx.headers(), RouteClass::Post, &v, html_response(page.body)?));
+    }
+
+    let contents = match client.get_post(title.clone()).await {
+        Ok(Some(post)) => post,
+        Ok(None) => return res_404(),
+        Err(e) => return res_503(&e),
+    };
+
+    let v = Validators::page(contents.revision, contents.updated_at.as_ref().map(String::as_str));
+    // Nothing to render if the client already has this revision.
+    if http_cache::is_fresh(cx.headers(), &v) {
+        return Ok(http_cache::respond(cx.headers(), RouteClass::Post, &v, html_response(String::new())?));
+    }
+
+    let assets = post_assets(&client, &contents.body).await;
+    let page = render_post(&contents, &assets)
+        .map_err(|e| {
+            let resp = http::Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header(http::header::CONTENT_TYPE, "text/html; charset=UTF-8")
+                .body(format!("<html><body>{:?}</body></html>", e).into())
+                .expect("Failed to build failure response");
+            Error::from(resp)
+        })?;
+    cache::put(&title, cache::CachedPage {
+        revision: contents.revision,
+        body: page.clone(),
+        updated_at: contents.updated_at.clone(),
+        rendered_on: None,
+    });
+    Ok(http_cache::respond(cx.headers(), RouteClass::Post, &v, html_response(page)?))
 }
@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+
+use ammonia::Builder;
+
+use crate::config::{self, SanitizeConfig};
+
+lazy_static! {
+    static ref ALLOWLIST: Allowlist = Allowlist::from_config(&config::get().render.sanitize);
+}
+
+/// Tags, attributes and URL schemes permitted in rendered post bodies, on top
+/// of ammonia's conservative defaults.
+#[derive(Debug, Clone)]
+pub struct Allowlist {
+    pub tags: HashSet<String>,
+    pub attributes: HashSet<String>,
+    pub url_schemes: HashSet<String>,
+}
+
+impl Allowlist {
+    pub fn from_config(cfg: &SanitizeConfig) -> Self {
+        // Markup generated by our own markdown pipeline: task list checkboxes,
+        // heading anchors, highlighter classes and responsive images.
+        let mut tags: HashSet<String> = ["input", "picture", "source"].iter().map(|s| s.to_string()).collect();
+        let mut attributes: HashSet<String> = [
+            "class", "id", "aria-hidden", "type", "checked", "disabled", "loading",
+        ].iter().map(|s| s.to_string()).collect();
+
+        tags.extend(cfg.allow_tags.iter().cloned());
+        attributes.extend(cfg.allow_attributes.iter().cloned());
+        // Only allowed where `clean_srcsets` expects it, whatever the config says.
+        attributes.remove("srcset");
+
+        Self { tags, attributes, url_schemes: cfg.url_schemes.clone() }
+    }
+}
+
+/// Tags ammonia removes along with their contents, and refuses to also allow.
+pub const RESERVED_TAGS: &[&str] = &["script", "style"];
+/// Attributes ammonia sets itself, and refuses to also allow: `rel` is added
+/// to every link.
+pub const RESERVED_ATTRIBUTES: &[&str] = &["rel"];
+
+/// Tags responsive images may give a `srcset`.
+const SRCSET_TAGS: &[&str] = &["img", "source"];
+
+/// Strip anything not on the allowlist from rendered HTML. `href` and `src`
+/// values with a scheme outside of the allowed set are removed entirely, and
+/// so are such candidates in a `srcset`.
+pub fn clean(html: &str) -> String {
+    let mut builder = Builder::new();
+    builder
+        .add_tags(ALLOWLIST.tags.iter())
+        .add_generic_attributes(ALLOWLIST.attributes.iter())
+        .url_schemes(ALLOWLIST.url_schemes.iter().map(String::as_str).collect())
+        .link_rel(Some("noopener noreferrer"));
+    for tag in SRCSET_TAGS {
+        builder.add_tag_attributes(*tag, ["srcset"].iter());
+    }
+    clean_srcsets(&builder.clean(html).to_string(), &ALLOWLIST.url_schemes)
+}
+
+/// Filter the candidates of every `srcset` in `html` down to those with a
+/// relative URL or one of `schemes`, dropping the attribute if none are left.
+/// ammonia checks the schemes of single-URL attributes like `src`, but not
+/// these lists. This relies on `html` being ammonia's output, in which `<`
+/// only ever starts a tag and attribute values are double-quoted with any
+/// `"` escaped.
+fn clean_srcsets(html: &str, schemes: &HashSet<String>) -> String {
+    const ATTRIBUTE: &str = " srcset=\"";
+    let bytes = html.as_bytes();
+    let mut out = String::with_capacity(html.len());
+    let (mut copied, mut i) = (0, 0);
+    let (mut in_tag, mut in_value) = (false, false);
+    while i < bytes.len() {
+        match bytes[i] {
+            b'<' if !in_tag => in_tag = true,
+            b'>' if in_tag && !in_value => in_tag = false,
+            b'"' if in_tag => in_value = !in_value,
+            b' ' if in_tag && !in_value && html[i..].starts_with(ATTRIBUTE) => {
+                let start = i + ATTRIBUTE.len();
+                let end = match html[start..].find('"') {
+                    Some(len) => start + len,
+                    None => break,
+                };
+                let kept = srcset_candidates(&html[start..end])
+                    .into_iter()
+                    .filter(|c| url_allowed(c.split_whitespace().next().unwrap_or_default(), schemes))
+                    .collect::<Vec<_>>();
+                out.push_str(&html[copied..i]);
+                if !kept.is_empty() {
+                    out.push_str(ATTRIBUTE);
+                    out.push_str(&kept.join(", "));
+                    out.push('"');
+                }
+                copied = end + 1;
+                i = end + 1;
+                continue;
+            },
+            _ => {},
+        }
+        i += 1;
+    }
+    out.push_str(&html[copied..]);
+    out
+}
+
+/// Split a `srcset` into its image candidates, each a URL and its optional
+/// descriptors, the way browsers do: a URL runs to the next whitespace and
+/// may itself contain commas, and descriptors run to the next comma.
+fn srcset_candidates(srcset: &str) -> Vec<&str> {
+    let mut candidates = vec![];
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+        if rest.is_empty() {
+            return candidates;
+        }
+        let url_len = rest.find(|c: char| c.is_ascii_whitespace()).unwrap_or_else(|| rest.len());
+        let len = if rest[..url_len].ends_with(',') {
+            url_len
+        } else {
+            rest[url_len..].find(',').map_or(rest.len(), |d| url_len + d)
+        };
+        candidates.push(rest[..len].trim_end_matches(|c: char| c.is_ascii_whitespace() || c == ','));
+        rest = &rest[len..];
+    }
+}
+
+/// Whether a URL is relative or uses one of `schemes`. Browsers ignore tabs
+/// and newlines anywhere in a URL, and leading control characters and
+/// spaces, so those can't be used to hide a scheme.
+fn url_allowed(url: &str, schemes: &HashSet<String>) -> bool {
+    let url: String = url.chars()
+        .filter(|c| !['\t', '\n', '\r'].contains(c))
+        .skip_while(|c| *c <= ' ')
+        .collect();
+    let colon = match url.find(':') {
+        Some(colon) => colon,
+        None => return true,
+    };
+    let scheme = &url[..colon];
+    let is_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    !is_scheme || schemes.contains(&scheme.to_ascii_lowercase())
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use tide::{http, Context, EndpointResult, error::ResultExt};
+use http::header::{self, HeaderValue};
+use http::status::StatusCode;
+
+use crate::db::Database;
+use crate::highlight;
+use crate::http_cache::{self, Validators};
+use crate::posts;
+
+/// Fingerprinted names change with their contents, so they can be cached
+/// for as long as clients are willing to.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// Plain names may change under the same URL and must be revalidated.
+const REVALIDATE: &str = "public, no-cache";
+
+lazy_static! {
+    static ref ASSETS: RwLock<AssetMap> = RwLock::new(AssetMap::default());
+}
+
+struct StaticFile {
+    body: Arc<Vec<u8>>,
+    content_type: String,
+    etag: String,
+    fingerprinted: bool,
+}
+
+/// A theme's static files, keyed by the name they're served under.
+#[derive(Default)]
+pub struct AssetMap {
+    /// Logical path, e.g. `css/site.css`, to its fingerprinted path.
+    urls: HashMap<String, String>,
+    /// Both the logical and fingerprinted paths of every file.
+    files: HashMap<String, StaticFile>,
+}
+
+impl AssetMap {
+    /// Read every file under `dir`. The stylesheet for the highlight theme is
+    /// added as `highlight.css` unless the theme ships its own.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let mut map = AssetMap::default();
+        map.add_dir(dir, "")?;
+        if !map.urls.contains_key("highlight.css") {
+            map.add("highlight.css", highlight::STYLESHEET.clone().into_bytes());
+        }
+        Ok(map)
+    }
+
+    fn add_dir(&mut self, dir: &Path, prefix: &str) -> io::Result<()> {
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(n) if !n.starts_with('.') => format!("{}{}", prefix, n),
+                _ => continue,
+            };
+            if path.is_dir() {
+                self.add_dir(&path, &format!("{}/", name))?;
+            } else {
+                self.add(&name, std::fs::read(&path)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, body: Vec<u8>) {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let hash = format!("{:016x}", hasher.finish());
+
+        let fingerprinted = fingerprint(name, &hash);
+        let content_type = mime_guess::guess_mime_type(name).to_string();
+        let body = Arc::new(body);
+        let file = |immutable: bool| StaticFile {
+            body: body.clone(),
+            content_type: content_type.clone(),
+            etag: format!("\"{}\"", hash),
+            fingerprinted: immutable,
+        };
+
+        self.files.insert(name.to_string(), file(false));
+        self.files.insert(fingerprinted.clone(), file(true));
+        self.urls.insert(name.to_string(), fingerprinted);
+    }
+
+    /// Combined hash of every file, for telling whether anything changed.
+    pub fn digest(&self) -> u64 {
+        let mut urls: Vec<_> = self.urls.values().collect();
+        urls.sort();
+        let mut hasher = DefaultHasher::new();
+        urls.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// `css/site.css` becomes `css/site.<hash>.css`.
+fn fingerprint(name: &str, hash: &str) -> String {
+    let (dir, file) = match name.rfind('/') {
+        Some(i) => name.split_at(i + 1),
+        None => ("", name),
+    };
+    match file.rfind('.') {
+        Some(i) if i > 0 => format!("{}{}.{}{}", dir, &file[..i], hash, &file[i..]),
+        _ => format!("{}{}.{}", dir, file, hash),
+    }
+}
+
+/// Replace the served files.
+pub fn set(assets: AssetMap) {
+    *ASSETS.write().unwrap() = assets;
+}
+
+/// URL of the current version of a static file, or `None` if the theme has
+/// no such file.
+pub fn url(path: &str) -> Option<String> {
+    let path = path.trim_start_matches('/');
+    ASSETS.read().unwrap().urls.get(path).map(|f| format!("/static/{}", f))
+}
+
+pub async fn serve(cx: Context<Database>) -> EndpointResult {
+    let path: String = cx.param("path").client_err()?;
+    let (body, content_type, etag, cache_control) = {
+        let assets = ASSETS.read().unwrap();
+        match assets.files.get(&path) {
+            Some(f) => (
+                f.body.clone(),
+                f.content_type.clone(),
+                f.etag.clone(),
+                if f.fingerprinted { IMMUTABLE } else { REVALIDATE },
+            ),
+            None => return posts::res_404(),
+        }
+    };
+
+    let v = Validators { etag, last_modified: None };
+    let mut res = if http_cache::is_fresh(cx.headers(), &v) {
+        http::Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Vec::new().into())
+            .expect("Error building response")
+    } else {
+        http::Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type)
+            .body((*body).clone().into())
+            .expect("Error building response")
+    };
+
+    let headers = res.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&v.etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    Ok(res)
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::RwLock;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use tera::{Tera, Value};
+
+use crate::cache;
+use crate::config::RenderConfig;
+use crate::static_files::{self, AssetMap};
+
+lazy_static! {
+    static ref TERA: RwLock<Tera> = RwLock::new(Tera::default());
+    static ref VERSION: RwLock<String> = RwLock::new(String::new());
+    static ref LOADED_AT: RwLock<DateTime<Utc>> = RwLock::new(Utc::now());
+}
+
+/// Render a template from the currently loaded set.
+pub fn render(template: &str, tera_ctx: &tera::Context) -> tera::Result<String> {
+    TERA.read().unwrap().render(template, tera_ctx)
+}
+
+/// Changes whenever the binary or any template changes, so that cached
+/// pages are revalidated after a deploy or reload even if no post was edited.
+pub fn version() -> String {
+    VERSION.read().unwrap().clone()
+}
+
+/// When `version` last changed. Pages are no older than this, whenever the
+/// posts on them were stored.
+pub fn loaded_at() -> DateTime<Utc> {
+    *LOADED_AT.read().unwrap()
+}
+
+/// Compile the theme's templates and read its static files, replacing the
+/// current theme only if both succeed.
+pub fn load(render: &RenderConfig) -> Result<(), String> {
+    let template_dir = render.templates();
+    let mut tera = Tera::new(&format!("{}/**/*.html", template_dir.display()))
+        .map_err(|e| format!("{:?}", e))?;
+    tera.register_function("static_url", Box::new(static_url));
+    let assets = AssetMap::load(&render.static_files())
+        .map_err(|e| format!("Can't read static files: {}", e))?;
+
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    hash_dir(&template_dir, &mut hasher);
+    // Pages embed fingerprinted asset URLs, so they change along with assets.
+    assets.digest().hash(&mut hasher);
+
+    static_files::set(assets);
+    *TERA.write().unwrap() = tera;
+    let version = format!("{:x}", hasher.finish());
+    if *VERSION.read().unwrap() != version {
+        *LOADED_AT.write().unwrap() = Utc::now();
+        *VERSION.write().unwrap() = version;
+    }
+    cache::clear();
+    Ok(())
+}
+
+/// `{{ static_url(path="css/site.css") }}` resolves to the fingerprinted URL
+/// of a file from the theme's `static` directory.
+fn static_url(args: HashMap<String, Value>) -> tera::Result<Value> {
+    let path = match args.get("path").and_then(Value::as_str) {
+        Some(p) => p,
+        None => return Err("static_url requires a `path` argument".into()),
+    };
+    static_files::url(path)
+        .map(Value::String)
+        .ok_or_else(|| format!("No static file {:?} in the theme", path).into())
+}
+
+fn hash_dir(dir: &Path, hasher: &mut DefaultHasher) {
+    let mut entries: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(Result::ok).map(|e| e.path()).collect(),
+        Err(_) => return,
+    };
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            hash_dir(&path, hasher);
+        } else if let Ok(contents) = std::fs::read(&path) {
+            path.hash(hasher);
+            contents.hash(hasher);
+        }
+    }
+}
+
+/// Reload the theme whenever one of its files changes. A theme that fails to
+/// load is logged and the last good one stays in use.
+pub fn watch(render: RenderConfig) -> notify::Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_millis(250))?;
+    watcher.watch(&render.templates(), RecursiveMode::Recursive)?;
+    if render.static_files().is_dir() {
+        watcher.watch(&render.static_files(), RecursiveMode::Recursive)?;
+    }
+
+    thread::spawn(move || {
+        // The watcher stops once dropped, so it lives as long as this thread.
+        let _watcher = watcher;
+        for event in rx {
+            match event {
+                DebouncedEvent::Create(_)
+                | DebouncedEvent::Write(_)
+                | DebouncedEvent::Remove(_)
+                | DebouncedEvent::Rename(_, _) => {
+                    match load(&render) {
+                        Ok(()) => eprintln!("Reloaded theme {:?}", render.theme),
+                        Err(e) => eprintln!("Keeping previous theme, reload failed: {}", e),
+                    }
+                },
+                DebouncedEvent::Error(e, _) => eprintln!("Theme watcher error: {}", e),
+                _ => {},
+            }
+        }
+    });
+    Ok(())
+}
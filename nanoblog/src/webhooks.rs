@@ -0,0 +1,520 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, Utc};
+use futures::executor::block_on;
+use hmac::{Hmac, Mac};
+use prometheus::{HistogramVec, IntCounter, IntCounterVec};
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use serde_json::json;
+use sha2::Sha256;
+use tide::{Context, EndpointResult};
+use tide::http::status::StatusCode;
+
+use crate::api;
+use crate::config::{self, WebhookConfig};
+use crate::db::{
+    Database, DeliveryStatus, Post, PostStatus, PostSummary, WebhookDelivery, WebhookEvent,
+};
+use crate::error::ApiError;
+
+/// How long the worker waits on an empty queue before checking again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Time a worker has to record an attempt once the receiver's timeout is up,
+/// before its lease runs out and the delivery goes to another worker.
+const LEASE_MARGIN_SECS: u64 = 30;
+/// Pause after a storage failure, so an unreachable Redis isn't hammered.
+const ERROR_BACKOFF: Duration = Duration::from_secs(5);
+const ID_LENGTH: usize = 20;
+
+lazy_static! {
+    static ref DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        "webhook_delivery_attempts_total",
+        "Webhook delivery attempts by subscription and outcome.",
+        &["subscription", "outcome"]
+    )
+    .unwrap();
+    static ref LATENCY: HistogramVec = register_histogram_vec!(
+        "webhook_delivery_duration_seconds",
+        "Time for webhook receivers to respond, in seconds.",
+        &["subscription"]
+    )
+    .unwrap();
+    static ref ENQUEUE_FAILURES: IntCounter = register_int_counter!(
+        "webhook_enqueue_failures_total",
+        "Webhook deliveries lost because they couldn't be queued."
+    )
+    .unwrap();
+}
+
+/// A change to report, before it's addressed to any subscription.
+#[derive(Debug, Clone)]
+pub struct Event {
+    kind: WebhookEvent,
+    slug: String,
+    post: Option<PostSummary>,
+}
+
+impl Event {
+    /// An event about `post`, which is on the index if `published`.
+    pub fn post(kind: WebhookEvent, post: &Post, published: bool) -> Self {
+        let summary = if published {
+            PostSummary::listed(post.clone().into(), &Local::today().format("%F").to_string())
+        } else {
+            PostSummary::new(post.clone().into(), PostStatus::Draft)
+        };
+        Self { kind, slug: post.slug.clone(), post: Some(summary) }
+    }
+
+    pub fn deleted(slug: String) -> Self {
+        Self { kind: WebhookEvent::PostDeleted, slug, post: None }
+    }
+}
+
+/// One delivery of each event to each subscription that wants it.
+fn deliveries(events: Vec<Event>) -> Vec<WebhookDelivery> {
+    let config = config::get();
+    let now = Utc::now().to_rfc3339();
+    let mut deliveries = vec![];
+    for event in events {
+        for sub in config.webhooks.subscriptions.iter().filter(|s| s.wants(event.kind)) {
+            let id: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(ID_LENGTH)
+                .collect();
+            let payload = json!({
+                "id": id,
+                "event": event.kind,
+                "created_at": now,
+                "data": { "slug": event.slug, "post": event.post },
+            });
+            deliveries.push(WebhookDelivery {
+                id,
+                subscription: sub.name.clone(),
+                event: event.kind,
+                payload,
+                created_at: now.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                last_attempt_at: None,
+                response_status: None,
+                error: None,
+                next_attempt_at: None,
+            });
+        }
+    }
+    deliveries
+}
+
+/// Queue `events` for every subscription that wants them. The changes they
+/// describe are stored by then, so a failure here is logged rather than
+/// failing the request.
+pub async fn fire(client: &Database, events: Vec<Event>) {
+    let deliveries = deliveries(events);
+    let count = deliveries.len();
+    if let Err(e) = client.enqueue_webhooks(deliveries).await {
+        eprintln!("Failed to queue {} webhook deliveries: {}", count, e);
+        ENQUEUE_FAILURES.inc_by(count as i64);
+    }
+}
+
+/// The `X-Nanoblog-Signature` header: the hex HMAC-SHA256 of the timestamp,
+/// a `.` and the body, keyed by the subscription's secret.
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.input(format!("{}.", timestamp).as_bytes());
+    mac.input(body);
+    format!("sha256={:x}", mac.result().code())
+}
+
+/// Wait before retrying a delivery that has failed `attempts` times.
+fn retry_delay(config: &WebhookConfig, attempts: u32) -> u64 {
+    let doublings = attempts.saturating_sub(1).min(32);
+    config.retry_delay_secs
+        .saturating_mul(1 << doublings)
+        .min(config.max_retry_delay_secs)
+}
+
+/// Post a delivery's payload once, returning the status the receiver
+/// responded with.
+fn send(http: &reqwest::Client, url: &str, secret: &str, delivery: &WebhookDelivery) -> Result<u16, String> {
+    let body = serde_json::to_vec(&delivery.payload).unwrap_or_default();
+    let timestamp = Utc::now().timestamp();
+    let signature = signature(secret, timestamp, &body);
+    let res = http.post(url)
+        .header("Content-Type", api::JSON)
+        .header("User-Agent", concat!("nanoblog/", env!("CARGO_PKG_VERSION")))
+        .header("X-Nanoblog-Event", delivery.event.as_str())
+        .header("X-Nanoblog-Delivery", delivery.id.as_str())
+        .header("X-Nanoblog-Timestamp", timestamp.to_string().as_str())
+        .header("X-Nanoblog-Signature", signature.as_str())
+        .body(body)
+        .send()
+        .map_err(|e| e.to_string())?;
+    Ok(res.status().as_u16())
+}
+
+/// Make one attempt at the queued `entry`, then log it and either retire it
+/// or schedule a retry.
+fn attempt(db: &Database, http: &reqwest::Client, entry: String) -> Result<(), String> {
+    let mut delivery: WebhookDelivery = match serde_json::from_str(&entry) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Dropping unreadable webhook delivery: {}", e);
+            return block_on(db.discard_webhook(entry));
+        },
+    };
+    let config = config::get();
+    let hooks = &config.webhooks;
+    let (outcome, retry_at) = deliver(http, hooks, &mut delivery, Utc::now());
+    DELIVERIES.with_label_values(&[&delivery.subscription, outcome]).inc();
+
+    block_on(db.finish_webhook(entry, delivery, retry_at, hooks.log_size))
+}
+
+/// Send `delivery` once at `now` and record how it went on it. Returns the
+/// outcome, for the metrics, and when to retry as a Unix timestamp if it
+/// should be retried.
+fn deliver(
+    http: &reqwest::Client,
+    hooks: &WebhookConfig,
+    delivery: &mut WebhookDelivery,
+    now: DateTime<Utc>,
+) -> (&'static str, Option<i64>) {
+    delivery.attempts += 1;
+    delivery.last_attempt_at = Some(now.to_rfc3339());
+    delivery.next_attempt_at = None;
+
+    // The subscription is looked up on every attempt, so a new url or secret
+    // applies to deliveries already queued.
+    let sub = hooks.subscriptions.iter().find(|s| s.name == delivery.subscription);
+    let result = match sub {
+        Some(sub) => {
+            let start = Instant::now();
+            let result = send(http, &sub.url, &sub.secret, &delivery);
+            LATENCY.with_label_values(&[&sub.name]).observe(start.elapsed().as_secs_f64());
+            result
+        },
+        None => Err("The subscription is no longer configured".into()),
+    };
+
+    let mut retry_at = None;
+    let outcome = match result {
+        Ok(status) if status >= 200 && status < 300 => {
+            delivery.status = DeliveryStatus::Delivered;
+            delivery.response_status = Some(status);
+            delivery.error = None;
+            "delivered"
+        },
+        failed => {
+            match failed {
+                Ok(status) => {
+                    delivery.response_status = Some(status);
+                    delivery.error = Some(format!("The receiver responded with {}", status));
+                },
+                Err(e) => {
+                    delivery.response_status = None;
+                    delivery.error = Some(e);
+                },
+            }
+            if sub.is_some() && delivery.attempts < hooks.max_attempts {
+                let due = now + chrono::Duration::seconds(retry_delay(hooks, delivery.attempts) as i64);
+                delivery.next_attempt_at = Some(due.to_rfc3339());
+                retry_at = Some(due.timestamp());
+                "retrying"
+            } else {
+                delivery.status = DeliveryStatus::Failed;
+                "failed"
+            }
+        },
+    };
+    (outcome, retry_at)
+}
+
+fn work(db: &Database, http: &reqwest::Client, lease: i64) -> Result<(), String> {
+    let now = Utc::now().timestamp();
+    block_on(db.promote_webhook_retries(now))?;
+    match block_on(db.expire_webhook_leases(now))? {
+        0 => {},
+        n => eprintln!("Requeued {} webhook deliveries whose worker stopped", n),
+    }
+    match block_on(db.next_webhook(now + lease))? {
+        Some(entry) => attempt(db, http, entry),
+        None => {
+            thread::sleep(POLL_INTERVAL);
+            Ok(())
+        },
+    }
+}
+
+/// Start the delivery worker, unless no subscriptions are configured. Every
+/// server runs one, sharing the queue, and deliveries are sent at least once:
+/// each is leased to the worker taking it, and one whose lease runs out
+/// before it's finished goes back on the queue, so receivers should ignore a
+/// repeated `X-Nanoblog-Delivery`.
+pub fn start(db: Database) {
+    let hooks = config::get().webhooks.clone();
+    if hooks.subscriptions.is_empty() {
+        return;
+    }
+    let lease = (hooks.timeout_secs + LEASE_MARGIN_SECS) as i64;
+    thread::spawn(move || {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(hooks.timeout_secs))
+            .build()
+            .expect("Can't build the webhook HTTP client");
+        loop {
+            // The connection pool panics when Redis is unreachable, which
+            // shouldn't take the worker down for good.
+            let res = panic::catch_unwind(AssertUnwindSafe(|| work(&db, &http, lease)))
+                .unwrap_or_else(|_| Err("the worker panicked".into()));
+            if let Err(e) = res {
+                eprintln!("Webhook delivery failed: {}", e);
+                thread::sleep(ERROR_BACKOFF);
+            }
+        }
+    });
+}
+
+/// The delivery log, newest attempt first, narrowed by the `subscription`
+/// and `status` query parameters. Payloads can describe drafts, so only
+/// admin tokens may read it.
+pub async fn list_deliveries(cx: Context<Database>) -> EndpointResult {
+    api::accept_json(cx.headers())?;
+    if !api::is_admin(&cx) {
+        return Err(ApiError::forbidden("Only admin tokens may read the webhook delivery log").into());
+    }
+    let status = match api::query_param(&cx, "status") {
+        Some(s) => Some(DeliveryStatus::parse(&s).ok_or_else(|| {
+            ApiError::bad_request(format!("Unknown status {:?}", s))
+                .with_details(json!({ "status": ["pending", "delivered", "failed"] }))
+        })?),
+        None => None,
+    };
+    let subscription = api::query_param(&cx, "subscription");
+
+    let client = cx.app_data().to_owned();
+    let deliveries: Vec<WebhookDelivery> = client.webhook_deliveries()
+        .await
+        .map_err(ApiError::storage)?
+        .into_iter()
+        .filter(|d| status.map_or(true, |s| d.status == s))
+        .filter(|d| subscription.as_ref().map_or(true, |s| d.subscription == *s))
+        .collect();
+    Ok(api::json_response(StatusCode::OK, &deliveries))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::ops::Deref;
+    use std::sync::mpsc;
+
+    use r2d2_redis::redis;
+
+    use super::*;
+    use crate::config::{Config, Subscription};
+
+    const SECRET: &str = "s3cret";
+
+    /// A request as the test receiver saw it.
+    struct Received {
+        path: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// Answer one connection per status in `statuses`, in order, on a local
+    /// port. Returns the URL to send to and the requests received.
+    fn receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split_whitespace().nth(1).unwrap_or_default().to_string();
+                let mut headers = HashMap::new();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    let header = line.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    if let Some(colon) = header.find(':') {
+                        headers.insert(header[..colon].to_ascii_lowercase(), header[colon + 1..].trim().to_string());
+                    }
+                }
+                let len = headers.get("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Test\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status,
+                ).unwrap();
+                tx.send(Received { path, headers, body }).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn hooks(url: &str) -> WebhookConfig {
+        WebhookConfig {
+            max_attempts: 3,
+            retry_delay_secs: 10,
+            max_retry_delay_secs: 15,
+            timeout_secs: 5,
+            log_size: 10,
+            subscriptions: vec![Subscription {
+                name: "test".into(),
+                url: url.into(),
+                secret: SECRET.into(),
+                events: vec![],
+            }],
+        }
+    }
+
+    fn delivery() -> WebhookDelivery {
+        WebhookDelivery {
+            id: "delivery-1".into(),
+            subscription: "test".into(),
+            event: WebhookEvent::PostCreated,
+            payload: json!({ "id": "delivery-1", "event": "post.created", "data": { "slug": "hello" } }),
+            created_at: "2019-06-01T00:00:00+00:00".into(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            last_attempt_at: None,
+            response_status: None,
+            error: None,
+            next_attempt_at: None,
+        }
+    }
+
+    fn http() -> reqwest::Client {
+        reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        let (url, received) = receiver(vec![204]);
+        let mut d = delivery();
+        let (outcome, retry_at) = deliver(&http(), &hooks(&url), &mut d, Utc::now());
+        assert_eq!((outcome, retry_at), ("delivered", None));
+
+        let req = received.recv().unwrap();
+        assert_eq!(req.path, "/hook");
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&req.body).unwrap(), d.payload);
+        assert_eq!(req.headers["x-nanoblog-event"], "post.created");
+        assert_eq!(req.headers["x-nanoblog-delivery"], "delivery-1");
+        let timestamp = &req.headers["x-nanoblog-timestamp"];
+        let mut mac = Hmac::<Sha256>::new_varkey(SECRET.as_bytes()).unwrap();
+        mac.input(timestamp.as_bytes());
+        mac.input(b".");
+        mac.input(&req.body);
+        assert_eq!(req.headers["x-nanoblog-signature"], format!("sha256={:x}", mac.result().code()));
+
+        assert_eq!(d.status, DeliveryStatus::Delivered);
+        assert_eq!(d.attempts, 1);
+        assert_eq!(d.response_status, Some(204));
+        assert_eq!(d.error, None);
+    }
+
+    #[test]
+    fn retries_failures_with_backoff_until_out_of_attempts() {
+        let (url, received) = receiver(vec![500, 503, 500]);
+        let hooks = hooks(&url);
+        let now = Utc::now();
+        let mut d = delivery();
+
+        assert_eq!(deliver(&http(), &hooks, &mut d, now), ("retrying", Some(now.timestamp() + 10)));
+        assert_eq!(d.status, DeliveryStatus::Pending);
+        assert_eq!(d.response_status, Some(500));
+        assert_eq!(d.error.as_ref().map(String::as_str), Some("The receiver responded with 500"));
+        assert_eq!(d.next_attempt_at, Some((now + chrono::Duration::seconds(10)).to_rfc3339()));
+
+        // The delay doubles, up to `max_retry_delay_secs`.
+        assert_eq!(deliver(&http(), &hooks, &mut d, now), ("retrying", Some(now.timestamp() + 15)));
+        assert_eq!(d.attempts, 2);
+        assert_eq!(d.response_status, Some(503));
+
+        assert_eq!(deliver(&http(), &hooks, &mut d, now), ("failed", None));
+        assert_eq!(d.status, DeliveryStatus::Failed);
+        assert_eq!(d.attempts, 3);
+        assert_eq!(d.next_attempt_at, None);
+        assert_eq!(received.iter().take(3).count(), 3);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        let hooks = WebhookConfig::default();
+        let delays: Vec<u64> = (1..=4).map(|n| retry_delay(&hooks, n)).collect();
+        assert_eq!(delays, vec![10, 20, 40, 80]);
+        assert_eq!(retry_delay(&hooks, 9), 2560);
+        assert_eq!(retry_delay(&hooks, 10), 3600);
+        assert_eq!(retry_delay(&hooks, 100), 3600);
+    }
+
+    #[test]
+    fn unreachable_receivers_are_retried() {
+        // Nothing listens on a port the OS just handed out and took back.
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/hook", listener.local_addr().unwrap())
+        };
+        let mut d = delivery();
+        let (outcome, _) = deliver(&http(), &hooks(&url), &mut d, Utc::now());
+        assert_eq!(outcome, "retrying");
+        assert_eq!(d.response_status, None);
+        assert!(d.error.is_some());
+    }
+
+    /// Runs the queue through Redis at `$NANOBLOG_TEST_REDIS` (by default
+    /// database 15 on localhost), which it flushes.
+    #[test]
+    #[ignore]
+    fn logs_every_attempt() {
+        let host = std::env::var("NANOBLOG_TEST_REDIS").unwrap_or_else(|_| "localhost/15".into());
+        let db = Database::new(Some(&host), None);
+        block_on(db.run(|conn| redis::cmd("FLUSHDB").query::<()>(conn.deref()))).unwrap();
+
+        let (url, _received) = receiver(vec![502, 200]);
+        config::set(Config { webhooks: hooks(&url), ..Config::default() });
+        let http = http();
+        let far_future = Utc::now().timestamp() + 86_400;
+
+        block_on(db.enqueue_webhooks(vec![delivery()])).unwrap();
+        let entry = block_on(db.next_webhook(far_future)).unwrap().unwrap();
+        attempt(&db, &http, entry).unwrap();
+        assert_eq!(block_on(db.next_webhook(far_future)).unwrap(), None);
+
+        block_on(db.promote_webhook_retries(far_future)).unwrap();
+        let entry = block_on(db.next_webhook(far_future)).unwrap().unwrap();
+        attempt(&db, &http, entry).unwrap();
+
+        let log = block_on(db.webhook_deliveries()).unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].id, "delivery-1");
+        assert_eq!(log[0].status, DeliveryStatus::Delivered);
+        assert_eq!((log[0].attempts, log[0].response_status), (2, Some(200)));
+        assert_eq!(log[1].status, DeliveryStatus::Pending);
+        assert_eq!((log[1].attempts, log[1].response_status), (1, Some(502)));
+        assert!(log[1].next_attempt_at.is_some());
+
+        // Nothing is left in flight to be handed out again.
+        assert_eq!(block_on(db.expire_webhook_leases(far_future + 1)).unwrap(), 0);
+        assert_eq!(block_on(db.next_webhook(far_future)).unwrap(), None);
+    }
+}
@@ -1,34 +1,67 @@
-use std::collections::HashMap;
+use std::path::Path;
 use difference::Changeset;
 extern crate nanoblog;
-use nanoblog::{NewPost, Post};
+use nanoblog::{
+    ApiError, Asset, BatchItemResult, BatchMode, BatchOperation, BatchRequest, BatchResponse,
+    NewPost, Post, PostSummary,
+};
+
+/// Operations sent per best-effort batch request, keeping each within the
+/// timeout.
+const BATCH_SIZE: usize = 50;
+/// Most operations the server accepts in one batch request.
+const MAX_BATCH_SIZE: usize = 500;
 
 pub struct Client {
     host: String,
     token: String,
     client: reqwest::Client,
+    /// Like `client`, with a timeout long enough for a whole atomic batch, or
+    /// for the server to resize an uploaded image.
+    slow_client: reqwest::Client,
 }
 
-type ListResponse = HashMap<String, String>;
+/// Narrows `Client::list_posts`, mirroring the server's query parameters.
+#[derive(Debug, Default)]
+pub struct ListFilter {
+    pub status: Option<String>,
+    pub tag: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// Pass successful responses through, and turn failures into the message the
+/// server gave.
+fn checked(mut res: reqwest::Response) -> Result<reqwest::Response, String> {
+    if res.status().is_success() {
+        return Ok(res);
+    }
+    match res.json::<ApiError>() {
+        Ok(e) => Err(e.to_string()),
+        Err(_) => Err(format!("Request failed: {}", res.status())),
+    }
+}
 
 
 impl Client {
     pub fn new(host: String, token: String) -> Result<Self, String> {
         use std::time::Duration;
 
-        let client = reqwest::Client::builder()
+        let build = |timeout| reqwest::Client::builder()
             .gzip(true)
-            .timeout(Duration::from_secs(3))
+            .timeout(Duration::from_secs(timeout))
             .danger_accept_invalid_certs(true)
             .build().unwrap();
+        let client = build(3);
+        let slow_client = build(60);
 
-        let c = Self { host, token, client };
+        let c = Self { host, token, client, slow_client };
         c.check()?;
         Ok(c)
     }
 
     fn url_for_path(&self, path: &str) -> String {
-        format!("https://{}/api/{}", &self.host, path)
+        format!("https://{}/api/v1/{}", &self.host, path)
     }
 
     fn get(&self, path: &str) -> reqwest::Result<reqwest::Response> {
@@ -36,6 +69,7 @@ impl Client {
 
         self.client.get(&url)
             .bearer_auth(&self.token)
+            .header(reqwest::header::ACCEPT, "application/json")
             .send()
     }
 
@@ -44,48 +78,149 @@ impl Client {
 
         self.client.post(&url)
             .bearer_auth(&self.token)
-            .header(reqwest::header::CONTENT_TYPE, "text/plain")
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(reqwest::header::ACCEPT, "application/json")
             .body(body)
             .send()
     }
 
-    pub fn get_post(&self, post: &str) -> Result<Post, String> {
-        self.get(&format!("posts/{}", post))
+    /// Change some of a post's fields, leaving the rest alone.
+    pub fn patch_post(&self, post: &str, patch: serde_json::Value) -> Result<Post, String> {
+        let res = self.client.patch(&self.url_for_path(&format!("posts/{}", post)))
+            .bearer_auth(&self.token)
+            .header(reqwest::header::CONTENT_TYPE, "application/merge-patch+json")
+            .header(reqwest::header::ACCEPT, "application/json")
+            .body(patch.to_string())
+            .send()
+            .map_err(|e| e.to_string())?;
+        checked(res)
+            .map_err(|e| format!("Failed to update {}: {}", post, e))?
+            .json()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Apply operations through the batch endpoint. An atomic batch goes in
+    /// a single request, so it is applied all or nothing; a best-effort one
+    /// a chunk at a time, and a chunk that can't be sent leaves the ones
+    /// after it unsent.
+    pub fn batch(&self, operations: Vec<BatchOperation>, mode: BatchMode) -> Result<Vec<BatchItemResult>, String> {
+        if let BatchMode::Atomic = mode {
+            if operations.len() > MAX_BATCH_SIZE {
+                return Err(format!(
+                    "{} operations are more than the {} an atomic batch can hold; nothing was sent",
+                    operations.len(), MAX_BATCH_SIZE,
+                ));
+            }
+            let request = BatchRequest { mode, operations };
+            return self.send_batch(&self.slow_client, &request).map(|r| r.results);
+        }
+
+        let total = operations.len();
+        let mut results = vec![];
+        let mut operations = operations.into_iter().peekable();
+        while operations.peek().is_some() {
+            let request = BatchRequest {
+                mode,
+                operations: operations.by_ref().take(BATCH_SIZE).collect(),
+            };
+            let response = self.send_batch(&self.client, &request).map_err(|e| format!(
+                "{}; {} of {} operations were applied or failed, and the other {} were not sent",
+                e, results.len(), total, total - results.len(),
+            ))?;
+            results.extend(response.results);
+        }
+        Ok(results)
+    }
+
+    fn send_batch(&self, client: &reqwest::Client, request: &BatchRequest) -> Result<BatchResponse, String> {
+        let res = client.post(&self.url_for_path("posts:batch"))
+            .bearer_auth(&self.token)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(reqwest::header::ACCEPT, "application/json")
+            .body(serde_json::to_string(request).unwrap())
+            .send()
+            .map_err(|e| e.to_string())?;
+        checked(res)?
+            .json()
+            .map_err(|e| format!("Bad batch response returned: {}", e))
+    }
+
+    /// Upload a file, returning the stored asset.
+    pub fn upload_asset(&self, path: &Path) -> Result<Asset, String> {
+        let name = path.file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| format!("Bad file name: {}", path.display()))?;
+        let data = std::fs::read(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+
+        let res = self.slow_client.post(&self.url_for_path("assets"))
+            .bearer_auth(&self.token)
+            .query(&[("name", name)])
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header(reqwest::header::ACCEPT, "application/json")
+            .body(data)
+            .send()
+            .map_err(|e| e.to_string())?;
+        checked(res)
+            .map_err(|e| format!("Failed to upload {}: {}", path.display(), e))?
+            .json()
             .map_err(|e| e.to_string())
-            .map(|mut r| {
-                let p: Post = r.json().unwrap_or_default();
-                Ok(p)
-            })?
+    }
+
+    /// Upload images referenced by relative paths in a post, resolved from
+    /// `base`, and point the references at the uploaded copies.
+    pub fn upload_images(&self, body: &str, base: &Path, dry_run: bool) -> Result<String, String> {
+        let mut body = body.to_string();
+        for image in local_images(&body) {
+            let path = base.join(&image);
+            if dry_run {
+                println!("Dry run: Not uploading {}", path.display());
+                continue;
+            }
+            let asset = self.upload_asset(&path)?;
+            println!("Uploaded {} as {}", path.display(), asset.url());
+            body = body.replace(&format!("]({}", image), &format!("]({}", asset.url()));
+        }
+        Ok(body)
+    }
+
+    pub fn get_post(&self, post: &str) -> Result<Post, String> {
+        let res = self.get(&format!("posts/{}", post))
+            .map_err(|e| e.to_string())?;
+        checked(res)?.json().map_err(|e| e.to_string())
     }
 
     fn check(&self) -> Result<(), String> {
         let res = self.get("ping")
             .map_err(|e| e.to_string())?;
+        checked(res)?;
+        Ok(())
+    }
 
-        match res.status() {
-            reqwest::StatusCode::OK => Ok(()),
-            _ => Err("Invalid token".into()),
-        }
+    pub fn list_posts(&self, filter: &ListFilter) -> Result<Vec<PostSummary>, String> {
+        let query: Vec<(&str, &str)> = vec![
+            ("status", &filter.status),
+            ("tag", &filter.tag),
+            ("from", &filter.from),
+            ("to", &filter.to),
+        ]
+            .into_iter()
+            .filter_map(|(k, v)| v.as_ref().map(|v| (k, v.as_str())))
+            .collect();
+
+        let res = self.client.get(&self.url_for_path("posts"))
+            .bearer_auth(&self.token)
+            .query(&query)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+            .map_err(|e| e.to_string())?;
+        checked(res)?
+            .json()
+            .map_err(|e| format!("Bad post list returned: {}", e))
     }
 
-    pub fn list_posts(&self, verbose: bool) -> Result<Vec<String>, String> {
-        self.get("posts")
-            .map_err(|e| e.to_string())
-            .map(|mut b| {
-                let kv: ListResponse = b.json().expect("Bad JSON returned");
-                kv.into_iter()
-                    .map(|(title, date)| {
-                        if verbose {
-                            format!("{} [{}]", title, date)
-                        } else {
-                            title.to_string()
-                        }
-                    })
-                    .collect()
-            })
-    }
-
-    pub fn publish(&self, title: &str, body: &str, dry_run: bool, diff: bool, draft: bool) -> Result<(), String> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn publish(&self, title: &str, body: &str, tags: Option<Vec<String>>, dry_run: bool, diff: bool, draft: bool, trusted_html: bool) -> Result<(), String> {
         if diff {
             let current_post = self.get_post(title).unwrap_or_default();
             let changeset = Changeset::new(&current_post.body, body, "\n");
@@ -105,15 +240,56 @@ impl Client {
         let post = NewPost {
             title: title.into(),
             body: body.into(),
+            trusted_html,
+            tags,
         };
 
-        self.post(&url, serde_json::to_string(&post).unwrap())
+        let res = self.post(&url, serde_json::to_string(&post).unwrap())
             .map_err(|e| e.to_string())?;
+        checked(res)?;
         Ok(())
     }
 
-    pub fn unpublish(&self, post: &str, dry_run: bool) -> Result<(), std::io::Error> {
+    pub fn unpublish(&self, post: &str, dry_run: bool) -> Result<(), String> {
+        if dry_run {
+            print!("Dry run: Not unpublishing");
+            return Ok(());
+        }
+
+        let res = self.post(&format!("posts/{}/unpublish", post), String::new())
+            .map_err(|e| e.to_string())?;
+        checked(res).map_err(|e| format!("Failed to unpublish {}: {}", post, e))?;
         Ok(())
     }
 }
 
+
+/// Targets of `![alt](target)` images that are paths relative to the post,
+/// rather than URLs or absolute paths.
+fn local_images(body: &str) -> Vec<String> {
+    let mut images = vec![];
+    let mut rest = body;
+    while let Some(start) = rest.find("![") {
+        rest = &rest[start + 2..];
+        let open = match rest.find("](") {
+            Some(i) => i + 2,
+            None => break,
+        };
+        rest = &rest[open..];
+        let target = rest.split(|c: char| c == ')' || c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches('<')
+            .trim_end_matches('>');
+
+        let remote = target.is_empty()
+            || target.contains("://")
+            || target.starts_with('/')
+            || target.starts_with('#')
+            || target.starts_with("data:");
+        if !remote && !images.iter().any(|i| i == target) {
+            images.push(target.to_string());
+        }
+    }
+    images
+}
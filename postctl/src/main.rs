@@ -2,9 +2,13 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io::prelude::*;
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use nanoblog::{BatchItemResult, BatchMode, BatchOperation, ExportedPost};
 
 mod api;
+mod sync;
 
 #[derive(serde::Deserialize, Debug)]
 struct Config {
@@ -18,10 +22,22 @@ struct Config {
 /// Control nanoblog contents
 enum Args {
     #[structopt(name = "list")]
-    /// list published posts
+    /// list posts, newest first
     List {
         #[structopt(short = "v")]
         verbose: bool,
+        /// published, scheduled or draft. Defaults to published and scheduled
+        #[structopt(long = "status")]
+        status: Option<String>,
+        /// Only posts with this tag
+        #[structopt(long = "tag")]
+        tag: Option<String>,
+        /// Only posts created on or after this YYYY-MM-DD date
+        #[structopt(long = "from")]
+        from: Option<String>,
+        /// Only posts created on or before this YYYY-MM-DD date
+        #[structopt(long = "to")]
+        to: Option<String>,
     },
     #[structopt(name = "get")]
     /// Get single post
@@ -29,7 +45,8 @@ enum Args {
         title: String,
     },
     #[structopt(name = "publish")]
-    /// publish a new post
+    /// publish a new post. Images referenced by relative paths are uploaded
+    /// and their links rewritten.
     Publish {
         #[structopt(long = "dry-run")]
         dry_run: bool,
@@ -42,10 +59,60 @@ enum Args {
         #[structopt(long = "draft")]
         draft: bool,
 
+        /// Render raw HTML in the post without sanitizing it. Requires an admin token.
+        #[structopt(long = "trusted-html")]
+        trusted_html: bool,
+
+        /// Tag the post, replacing its current tags. Repeat for several.
+        #[structopt(long = "tag")]
+        tags: Vec<String>,
+
+        /// Publish an already saved post, named by its slug, instead of uploading one
+        #[structopt(long = "existing")]
+        existing: bool,
+
         title: String,
 
-        #[structopt(name = "file")]
-        post: PathBuf,
+        #[structopt(name = "file", parse(from_os_str), required_unless = "existing")]
+        post: Option<PathBuf>,
+    },
+    #[structopt(name = "set-title")]
+    /// Retitle a post, keeping its slug
+    SetTitle {
+        post: String,
+        title: String,
+    },
+    #[structopt(name = "set-tags")]
+    /// Replace a post's tags. Give none to clear them
+    SetTags {
+        post: String,
+        tags: Vec<String>,
+    },
+    #[structopt(name = "sync")]
+    /// Make the blog match a directory of `.md` posts, each naming its title
+    /// (and optionally tags, draft and date) in `+++` TOML front matter
+    Sync {
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Delete posts on the blog that aren't in the directory
+        #[structopt(long = "prune")]
+        prune: bool,
+        /// Apply what can be applied, rather than nothing if anything fails
+        #[structopt(long = "best-effort")]
+        best_effort: bool,
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(name = "import")]
+    /// Recreate posts from a `nanoblog export` file, keeping their dates
+    Import {
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Apply what can be applied, rather than nothing if anything fails
+        #[structopt(long = "best-effort")]
+        best_effort: bool,
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
     },
     #[structopt(name = "unpublish")]
     /// Unpublish published post
@@ -57,6 +124,42 @@ enum Args {
 }
 
 
+fn batch_mode(best_effort: bool) -> BatchMode {
+    if best_effort { BatchMode::BestEffort } else { BatchMode::Atomic }
+}
+
+/// Print the outcome of each batch operation, failing if any of them failed.
+fn report(results: &[BatchItemResult]) -> Result<(), std::io::Error> {
+    let mut failures = 0;
+    for result in results {
+        match &result.error {
+            Some(e) => {
+                failures += 1;
+                println!("{} {}: {}", result.op, result.slug, e);
+            },
+            None => println!("{} {}: ok", result.op, result.slug),
+        }
+    }
+    if failures > 0 {
+        return Err(Error::new(ErrorKind::Other, format!("{} of {} operations failed", failures, results.len())));
+    }
+    Ok(())
+}
+
+/// Print operations instead of sending them.
+fn dry_run(operations: &[BatchOperation]) {
+    for op in operations {
+        match op {
+            BatchOperation::Upsert { post, draft, .. } => {
+                println!("Dry run: upsert {:?}{}", post.title, if *draft { " as a draft" } else { "" });
+            },
+            BatchOperation::Publish { slug } => println!("Dry run: publish {}", slug),
+            BatchOperation::Unpublish { slug } => println!("Dry run: unpublish {}", slug),
+            BatchOperation::Delete { slug } => println!("Dry run: delete {}", slug),
+        }
+    }
+}
+
 fn load_config() -> Result<Config, std::io::Error> {
     let home = std::env::var("HOME").expect("No home directory detectable, wat");
     let path = format!("{}/.config/blogctl/config.json", home);
@@ -75,12 +178,22 @@ fn main(args: Args) -> Result<(), std::io::Error> {
         .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
 
     match args {
-        Args::List {verbose} => {
+        Args::List {verbose, status, tag, from, to} => {
+            let filter = api::ListFilter { status, tag, from, to };
             let posts = client
-                .list_posts(verbose)
+                .list_posts(&filter)
                 .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
             for post in posts {
-                println!("{}", post);
+                if verbose {
+                    let tags = if post.tags.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" #{}", post.tags.join(" #"))
+                    };
+                    println!("{} [{}, {}] {}{}", post.slug, post.status.as_str(), post.date_created, post.title, tags);
+                } else {
+                    println!("{}", post.slug);
+                }
             }
         },
         Args::Get {title} => {
@@ -88,15 +201,84 @@ fn main(args: Args) -> Result<(), std::io::Error> {
                 .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
             println!("{:?}", post);
         },
-        Args::Publish {title, post, dry_run, diff, draft} => {
+        Args::Publish {title, existing: true, dry_run, draft, ..} => {
+            if draft {
+                return Err(Error::new(ErrorKind::InvalidInput, "--existing can't be combined with --draft"));
+            }
+            if dry_run {
+                print!("Dry run: Not publishing");
+                return Ok(());
+            }
+            client.patch_post(&title, serde_json::json!({ "draft": false }))
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        },
+        Args::Publish {title, post, dry_run, diff, draft, trusted_html, tags, ..} => {
+            let post = post.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No file to publish"))?;
             let mut buf = String::new();
-            let mut file = File::open(post)?;
+            let mut file = File::open(&post)?;
             file.read_to_string(&mut buf)?;
-            client.publish(&title, &buf, dry_run, diff, draft)
+            let base = post.parent().unwrap_or_else(|| Path::new(""));
+            let buf = client.upload_images(&buf, base, dry_run)
+                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            // Without --tag, the post keeps whatever tags it already has.
+            let tags = if tags.is_empty() { None } else { Some(tags) };
+            client.publish(&title, &buf, tags, dry_run, diff, draft, trusted_html)
                 .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
         },
+        Args::SetTitle {post, title} => {
+            client.patch_post(&post, serde_json::json!({ "title": title }))
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        },
+        Args::SetTags {post, tags} => {
+            client.patch_post(&post, serde_json::json!({ "tags": tags }))
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        },
+        Args::Sync {dry_run: dry, prune, best_effort, dir} => {
+            let other = |e: String| Error::new(ErrorKind::Other, e);
+            let posts = sync::read_dir(&dir).map_err(other)?;
+            let local: HashSet<String> = posts.iter().map(|p| p.slug.clone()).collect();
+
+            let mut operations = vec![];
+            for post in posts {
+                let body = client.upload_images(&post.body, &dir, dry).map_err(other)?;
+                operations.extend(post.operations(body));
+            }
+            if prune {
+                let filters = vec![
+                    api::ListFilter::default(),
+                    api::ListFilter { status: Some("draft".into()), ..api::ListFilter::default() },
+                ];
+                for filter in filters {
+                    for post in client.list_posts(&filter).map_err(other)? {
+                        if !local.contains(&post.slug) {
+                            operations.push(BatchOperation::Delete { slug: post.slug });
+                        }
+                    }
+                }
+            }
+
+            if dry {
+                dry_run(&operations);
+                return Ok(());
+            }
+            report(&client.batch(operations, batch_mode(best_effort)).map_err(other)?)?;
+        },
+        Args::Import {dry_run: dry, best_effort, file} => {
+            let posts: Vec<ExportedPost> = serde_json::from_reader(BufReader::new(File::open(&file)?))?;
+            let operations: Vec<BatchOperation> = posts.into_iter()
+                .flat_map(sync::import_operations)
+                .collect();
+            if dry {
+                dry_run(&operations);
+                return Ok(());
+            }
+            let results = client.batch(operations, batch_mode(best_effort))
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            report(&results)?;
+        },
         Args::Unpublish {dry_run, post} => {
-            client.unpublish(&post, dry_run)?;
+            client.unpublish(&post, dry_run)
+                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
         },
     };
     Ok(())
@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nanoblog::{BatchOperation, ExportedPost, NewPost};
+use serde::Deserialize;
+use slug::slugify;
+
+const FRONT_MATTER_DELIM: &str = "+++";
+
+/// The post settings read from a file's `+++` front matter. The server reads
+/// its rendering options from the same block and ignores these.
+#[derive(Deserialize, Debug, Default)]
+struct FrontMatter {
+    title: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    draft: bool,
+    /// Creation date, `YYYY-MM-DD`, for posts written before they're synced.
+    date: Option<String>,
+}
+
+impl FrontMatter {
+    fn parse(contents: &str) -> Result<Self, String> {
+        let start = format!("{}\n", FRONT_MATTER_DELIM);
+        let end = format!("\n{}\n", FRONT_MATTER_DELIM);
+        if !contents.starts_with(&start) {
+            return Ok(Self::default());
+        }
+        let rest = &contents[start.len()..];
+        match rest.find(&end) {
+            Some(idx) => toml::from_str(&rest[..idx]).map_err(|e| e.to_string()),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// A post file from a synced directory.
+#[derive(Debug)]
+pub struct LocalPost {
+    pub path: PathBuf,
+    pub slug: String,
+    title: String,
+    pub body: String,
+    tags: Vec<String>,
+    draft: bool,
+    date: Option<String>,
+}
+
+impl LocalPost {
+    fn read(path: PathBuf) -> Result<Self, String> {
+        let body = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let fm = FrontMatter::parse(&body)
+            .map_err(|e| format!("Bad front matter in {}: {}", path.display(), e))?;
+        let title = fm.title
+            .ok_or_else(|| format!("{} has no `title` in its front matter", path.display()))?;
+        Ok(Self {
+            path,
+            slug: slugify(&title),
+            title,
+            body,
+            tags: fm.tags,
+            draft: fm.draft,
+            date: fm.date,
+        })
+    }
+
+    /// The operations bringing the server's copy in line with this file,
+    /// given its body with image links rewritten.
+    pub fn operations(self, body: String) -> Vec<BatchOperation> {
+        let mut ops = vec![BatchOperation::Upsert {
+            post: NewPost {
+                title: self.title,
+                body,
+                trusted_html: false,
+                tags: Some(self.tags),
+            },
+            draft: self.draft,
+            date_created: self.date,
+            slug: None,
+        }];
+        // An upsert as a draft only saves the post; one that was published
+        // before has to be taken off the index too.
+        if self.draft {
+            ops.push(BatchOperation::Unpublish { slug: self.slug });
+        }
+        ops
+    }
+}
+
+/// Every `.md` file directly inside `dir`, in name order.
+pub fn read_dir(dir: &Path) -> Result<Vec<LocalPost>, String> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("Can't read {}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().map_or(false, |e| e == "md"))
+        .collect();
+    paths.sort();
+    paths.into_iter().map(LocalPost::read).collect()
+}
+
+/// The operations recreating a post from `nanoblog export`, keeping its
+/// original date.
+pub fn import_operations(exported: ExportedPost) -> Vec<BatchOperation> {
+    let ExportedPost { post, published } = exported;
+    let slug = post.slug.clone();
+    let mut ops = vec![BatchOperation::Upsert {
+        post: NewPost {
+            title: post.title,
+            body: post.body,
+            trusted_html: post.trusted_html,
+            tags: Some(post.tags),
+        },
+        draft: !published,
+        date_created: Some(post.date_created),
+        slug: Some(slug.clone()),
+    }];
+    if !published {
+        ops.push(BatchOperation::Unpublish { slug });
+    }
+    ops
+}